@@ -0,0 +1,399 @@
+//! On-disk cache of fixed-size file blocks
+//!
+//! Blocks live in `<workdir>/cache/blocks/<sha1 of path>/<block index>` next
+//! to a `meta` file recording the size and modification time of the file they
+//! were downloaded from, blocks of a file that changed upstream are dropped.
+//! Blocks of pinned files are never evicted.
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tracing::{debug, error, info, warn};
+
+use crate::drive::AlistFile;
+
+/// Size of a cached block, the last block of a file may be shorter
+pub const BLOCK_SIZE: u64 = 4 * 1024 * 1024;
+
+const META_FILE: &str = "meta";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct BlockMeta {
+    path: String,
+    size: u64,
+    modified: u64,
+    #[serde(default)]
+    pinned: bool,
+}
+
+impl BlockMeta {
+    fn new(file: &AlistFile) -> Self {
+        Self {
+            path: file.path.clone(),
+            size: file.file.size,
+            modified: modified_secs(file),
+            pinned: false,
+        }
+    }
+
+    fn same_file(&self, other: &Self) -> bool {
+        self.path == other.path && self.size == other.size && self.modified == other.modified
+    }
+
+    fn read(dir: &Path) -> Option<Self> {
+        fs::read(dir.join(META_FILE))
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+    }
+
+    fn write(&self, dir: &Path) -> std::io::Result<()> {
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join(META_FILE), serde_json::to_vec(self)?)
+    }
+}
+
+/// Name of the cache entry of a file path
+pub fn path_hash(path: &str) -> String {
+    let mut hasher = Sha1::default();
+    hasher.update(path.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// Modification time of a file in seconds since the epoch
+pub fn modified_secs(file: &AlistFile) -> u64 {
+    file.file
+        .modified
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+type BlockKey = (String, u64);
+
+#[derive(Debug)]
+struct BlockEntry {
+    size: u64,
+    last_access: u64,
+}
+
+#[derive(Debug)]
+struct Inner {
+    dir: PathBuf,
+    max_size: u64,
+    used: u64,
+    clock: u64,
+    blocks: HashMap<BlockKey, BlockEntry>,
+    // last access -> block, oldest first
+    lru: BTreeMap<u64, BlockKey>,
+    // hash -> path of pinned files
+    pinned: HashMap<String, String>,
+}
+
+impl Inner {
+    fn block_path(&self, key: &BlockKey) -> PathBuf {
+        self.dir.join(&key.0).join(key.1.to_string())
+    }
+
+    fn touch(&mut self, key: &BlockKey) -> bool {
+        self.clock += 1;
+        let clock = self.clock;
+        match self.blocks.get_mut(key) {
+            Some(entry) => {
+                self.lru.remove(&entry.last_access);
+                entry.last_access = clock;
+                self.lru.insert(clock, key.clone());
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn insert(&mut self, key: BlockKey, size: u64) {
+        self.remove(&key);
+        self.clock += 1;
+        self.used += size;
+        self.lru.insert(self.clock, key.clone());
+        self.blocks.insert(
+            key,
+            BlockEntry {
+                size,
+                last_access: self.clock,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &BlockKey) -> bool {
+        match self.blocks.remove(key) {
+            Some(entry) => {
+                self.lru.remove(&entry.last_access);
+                self.used -= entry.size;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Delete least recently used blocks until we are under the size limit
+    fn evict(&mut self) {
+        while self.used > self.max_size {
+            let pinned = &self.pinned;
+            let key = match self.lru.values().find(|(hash, _)| !pinned.contains_key(hash)) {
+                Some(key) => key.clone(),
+                None => {
+                    warn!(size = self.used, "pinned files exceed the block cache size limit");
+                    break;
+                }
+            };
+            self.remove(&key);
+            let path = self.block_path(&key);
+            debug!(path = %path.display(), "evict cached block");
+            if let Err(err) = fs::remove_file(&path) {
+                error!(path = %path.display(), error = %err, "remove cached block failed");
+            }
+        }
+    }
+
+    fn purge_file(&mut self, hash: &str) {
+        let keys: Vec<_> = self
+            .blocks
+            .keys()
+            .filter(|(h, _)| h == hash)
+            .cloned()
+            .collect();
+        for key in keys {
+            self.remove(&key);
+        }
+        let dir = self.dir.join(hash);
+        if let Err(err) = fs::remove_dir_all(&dir) {
+            error!(dir = %dir.display(), error = %err, "remove cached file failed");
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BlockCache {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl BlockCache {
+    /// Open the cache in `dir`, picking up blocks cached by earlier runs
+    pub fn new(dir: PathBuf, max_size: u64) -> std::io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let mut inner = Inner {
+            dir,
+            max_size,
+            used: 0,
+            clock: 0,
+            blocks: HashMap::new(),
+            lru: BTreeMap::new(),
+            pinned: HashMap::new(),
+        };
+        let mut found = Vec::new();
+        for entry in fs::read_dir(&inner.dir)?.flatten() {
+            if !entry.path().is_dir() {
+                continue;
+            }
+            let hash = entry.file_name().to_string_lossy().into_owned();
+            let meta = match BlockMeta::read(&entry.path()) {
+                Some(meta) => meta,
+                None => {
+                    let _ = fs::remove_dir_all(entry.path());
+                    continue;
+                }
+            };
+            if meta.pinned {
+                inner.pinned.insert(hash.clone(), meta.path);
+            }
+            for block in fs::read_dir(entry.path())?.flatten() {
+                let index = match block.file_name().to_string_lossy().parse::<u64>() {
+                    Ok(index) => index,
+                    Err(_) => continue,
+                };
+                if let Ok(meta) = block.metadata() {
+                    let modified = meta.modified().unwrap_or(UNIX_EPOCH);
+                    found.push((modified, (hash.clone(), index), meta.len()));
+                }
+            }
+        }
+        // blocks written last are the most recently used ones we know of
+        found.sort_by_key(|(modified, _, _)| *modified);
+        for (_, key, size) in found {
+            inner.insert(key, size);
+        }
+        inner.evict();
+        info!(
+            dir = %inner.dir.display(),
+            blocks = inner.blocks.len(),
+            size = inner.used,
+            "block cache loaded"
+        );
+        Ok(Self {
+            inner: Arc::new(Mutex::new(inner)),
+        })
+    }
+
+    /// Cached blocks of `file`, dropping the ones of an older version of it
+    pub fn open(&self, file: &AlistFile) -> FileBlocks {
+        let hash = path_hash(&file.path);
+        let mut meta = BlockMeta::new(file);
+        let mut inner = self.inner.lock();
+        let dir = inner.dir.join(&hash);
+        let cached_meta = BlockMeta::read(&dir);
+        if !cached_meta.as_ref().map(|m| m.same_file(&meta)).unwrap_or(false) {
+            if let Some(cached_meta) = cached_meta {
+                info!(path = %file.path, "file changed, drop cached blocks");
+                inner.purge_file(&hash);
+                // a new version of a pinned file stays pinned
+                meta.pinned = cached_meta.pinned;
+            }
+            if let Err(err) = meta.write(&dir) {
+                error!(path = %file.path, error = %err, "write block cache meta failed");
+            }
+        }
+        FileBlocks {
+            cache: self.clone(),
+            hash,
+            file_size: file.file.size,
+        }
+    }
+
+    /// Unpin every file at or below `path`
+    pub fn unpin(&self, path: &str) {
+        let mut inner = self.inner.lock();
+        let dir_prefix = format!("{}/", path.trim_end_matches('/'));
+        let hashes: Vec<_> = inner
+            .pinned
+            .iter()
+            .filter(|(_, p)| p.as_str() == path || p.starts_with(&dir_prefix))
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        for hash in hashes {
+            inner.pinned.remove(&hash);
+            let dir = inner.dir.join(&hash);
+            if let Some(mut meta) = BlockMeta::read(&dir) {
+                meta.pinned = false;
+                if let Err(err) = meta.write(&dir) {
+                    error!(path = %meta.path, error = %err, "write block cache meta failed");
+                }
+            }
+        }
+        inner.evict();
+    }
+}
+
+/// The cached blocks of one version of a file
+#[derive(Debug, Clone)]
+pub struct FileBlocks {
+    cache: BlockCache,
+    hash: String,
+    file_size: u64,
+}
+
+impl FileBlocks {
+    fn block_len(&self, index: u64) -> u64 {
+        BLOCK_SIZE.min(self.file_size.saturating_sub(index * BLOCK_SIZE))
+    }
+
+    fn key(&self, index: u64) -> BlockKey {
+        (self.hash.clone(), index)
+    }
+
+    pub fn contains(&self, offset: u64) -> bool {
+        let key = self.key(offset / BLOCK_SIZE);
+        self.cache.inner.lock().blocks.contains_key(&key)
+    }
+
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    /// Exempt the blocks of the file from eviction
+    pub fn pin(&self, path: &str) {
+        let mut inner = self.cache.inner.lock();
+        if inner.pinned.contains_key(&self.hash) {
+            return;
+        }
+        inner.pinned.insert(self.hash.clone(), path.to_string());
+        let dir = inner.dir.join(&self.hash);
+        if let Some(mut meta) = BlockMeta::read(&dir) {
+            meta.pinned = true;
+            if let Err(err) = meta.write(&dir) {
+                error!(path = %path, error = %err, "write block cache meta failed");
+            }
+        }
+    }
+
+    /// Read up to `size` bytes at `offset` if the block holding it is cached,
+    /// never reads past the end of that block
+    pub fn read(&self, offset: u64, size: usize) -> Option<Bytes> {
+        let index = offset / BLOCK_SIZE;
+        let key = self.key(index);
+        let path = {
+            let mut inner = self.cache.inner.lock();
+            if !inner.touch(&key) {
+                return None;
+            }
+            inner.block_path(&key)
+        };
+        let block_offset = offset - index * BLOCK_SIZE;
+        let len = (size as u64).min(self.block_len(index).saturating_sub(block_offset));
+        let mut buf = vec![0; len as usize];
+        let res = File::open(&path).and_then(|mut f| {
+            f.seek(SeekFrom::Start(block_offset))?;
+            f.read_exact(&mut buf)
+        });
+        match res {
+            Ok(_) => Some(buf.into()),
+            Err(err) => {
+                error!(path = %path.display(), error = %err, "read cached block failed");
+                self.cache.inner.lock().remove(&key);
+                None
+            }
+        }
+    }
+
+    /// Store the blocks that `data`, starting at `offset`, fully covers
+    pub fn write(&self, offset: u64, data: &[u8]) {
+        let end = offset + data.len() as u64;
+        let mut index = offset.div_ceil(BLOCK_SIZE);
+        loop {
+            let block_start = index * BLOCK_SIZE;
+            let block_len = self.block_len(index);
+            if block_len == 0 || block_start + block_len > end {
+                break;
+            }
+            let key = self.key(index);
+            if !self.contains(block_start) {
+                let start = (block_start - offset) as usize;
+                let block = &data[start..start + block_len as usize];
+                self.write_block(key, block);
+            }
+            index += 1;
+        }
+    }
+
+    fn write_block(&self, key: BlockKey, block: &[u8]) {
+        let path = self.cache.inner.lock().block_path(&key);
+        let tmp_path = path.with_extension("tmp");
+        let res = fs::write(&tmp_path, block).and_then(|_| fs::rename(&tmp_path, &path));
+        if let Err(err) = res {
+            error!(path = %path.display(), error = %err, "write cached block failed");
+            let _ = fs::remove_file(&tmp_path);
+            return;
+        }
+        let mut inner = self.cache.inner.lock();
+        if !inner.dir.join(&key.0).exists() {
+            // the file was invalidated while we were writing
+            return;
+        }
+        inner.insert(key, block.len() as u64);
+        inner.evict();
+    }
+}
@@ -1,50 +1,112 @@
 use std::path::Path;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use moka::future::{Cache as MokaCache, CacheBuilder};
 use tracing::{trace,debug};
 
 use crate::drive::model::AlistFile;
+use crate::ttl::TtlPolicy;
 
 #[derive(Clone)]
 pub struct Cache {
-    inner: MokaCache<String, Vec<AlistFile>>,
+    // path -> (listed at, files)
+    inner: MokaCache<String, (Instant, Vec<AlistFile>)>,
+    ttl: TtlPolicy,
 }
 
 impl Cache {
-    pub fn new(max_capacity: u64, ttl: u64) -> Self {
+    /// Listings expire after the directory listing lifetime `ttl` gives their path
+    pub fn new(max_capacity: u64, ttl: TtlPolicy) -> Self {
         let inner = CacheBuilder::new(max_capacity)
-            .time_to_live(Duration::from_secs(ttl))
+            .time_to_live(ttl.max_dir())
+            .support_invalidation_closures()
             .build();
-        Self { inner }
+        Self { inner, ttl }
     }
 
     #[allow(clippy::ptr_arg)]
     pub fn get(&self, key: &String) -> Option<Vec<AlistFile>> {
         trace!(key = %key, "cache: get");
-        self.inner.get(key)
+        let (listed_at, files) = self.inner.get(key)?;
+        if listed_at.elapsed() >= self.ttl.get(key).dir {
+            trace!(key = %key, "cache: expired");
+            return None;
+        }
+        Some(files)
     }
 
-    pub async fn insert(&self, key: String, value: Vec<AlistFile>) {
+    pub fn insert(&self, key: String, value: Vec<AlistFile>) {
         trace!(key = %key, "cache: insert");
-        self.inner.insert(key, value).await;
+        self.inner.blocking().insert(key, (Instant::now(), value));
     }
 
-    pub async fn invalidate(&self, path: &Path) {
+    pub fn invalidate(&self, path: &Path) {
         let key = path.to_string_lossy().into_owned();
         debug!(path = %path.display(), key = %key, "cache: invalidate");
-        self.inner.invalidate(&key).await;
+        self.inner.blocking().invalidate(&key);
     }
 
-    pub async fn invalidate_parent(&self, path: &Path) {
-        if let Some(parent) = path.parent() {
-            self.invalidate(parent).await;
+    /// Invalidate a directory and every directory below it
+    pub fn invalidate_tree(&self, path: &Path) {
+        self.invalidate(path);
+        let prefix = format!("{}/", path.to_string_lossy().trim_end_matches('/'));
+        debug!(prefix = %prefix, "cache: invalidate tree");
+        if let Err(err) = self
+            .inner
+            .invalidate_entries_if(move |key, _| key.starts_with(&prefix))
+        {
+            debug!(error = %err, "cache: invalidate tree failed");
         }
     }
+}
 
+/// Paths known not to exist
+#[derive(Clone)]
+pub struct NegativeCache {
+    inner: MokaCache<String, ()>,
+    ttl: Duration,
+}
 
-    pub fn invalidate_all(&self) {
-        trace!("cache: invalidate all");
-        self.inner.invalidate_all();
+impl NegativeCache {
+    /// A `ttl` of 0 disables the cache
+    pub fn new(max_capacity: u64, ttl: u64) -> Self {
+        let ttl = Duration::from_secs(ttl);
+        let inner = CacheBuilder::new(max_capacity)
+            .time_to_live(ttl.max(Duration::from_secs(1)))
+            .support_invalidation_closures()
+            .build();
+        Self { inner, ttl }
     }
-}
\ No newline at end of file
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn contains(&self, path: &str) -> bool {
+        self.inner.get(path).is_some()
+    }
+
+    pub fn insert(&self, path: String) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        trace!(path = %path, "negative cache: insert");
+        self.inner.blocking().insert(path, ());
+    }
+
+    pub fn invalidate(&self, path: &str) {
+        self.inner.blocking().invalidate(path);
+    }
+
+    /// Forget the missing entries of a directory that was listed or changed
+    pub fn invalidate_children(&self, dir: &str) {
+        let prefix = format!("{}/", dir.trim_end_matches('/'));
+        debug!(prefix = %prefix, "negative cache: invalidate children");
+        if let Err(err) = self
+            .inner
+            .invalidate_entries_if(move |key, _| key.starts_with(&prefix))
+        {
+            debug!(error = %err, "negative cache: invalidate children failed");
+        }
+    }
+}
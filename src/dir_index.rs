@@ -0,0 +1,160 @@
+//! Persistent index of directory listings
+//!
+//! Every listing fetched from the server is written to
+//! `<workdir>/cache/index/<sha1 of path>.json`, so after a restart directories
+//! can be served from disk right away. A listing served from the index is
+//! fetched again in the background and the directory cache updated with it.
+//! While the server can't be reached, the saved listings are served as is.
+//! The listings of directories that were removed or moved are dropped.
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
+
+use crate::block_cache::path_hash;
+use crate::cache::Cache;
+use crate::drive::{AlistDrive, AlistFile};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct IndexEntry {
+    path: String,
+    files: Vec<AlistFile>,
+}
+
+struct Inner {
+    dir: PathBuf,
+    dir_cache: Cache,
+    // paths listed from the server since startup, their index entry is up to date
+    fresh: Mutex<HashSet<String>>,
+    queue: Mutex<Sender<String>>,
+}
+
+#[derive(Clone)]
+pub struct DirIndex {
+    inner: Arc<Inner>,
+}
+
+impl DirIndex {
+    /// Open the index in `dir`, listings served from it are revalidated with
+    /// `drive` and the fresh ones put into `dir_cache`
+    pub fn new(dir: PathBuf, drive: AlistDrive, dir_cache: Cache) -> std::io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let (tx, rx) = mpsc::channel();
+        let index = Self {
+            inner: Arc::new(Inner {
+                dir,
+                dir_cache: dir_cache.clone(),
+                fresh: Mutex::new(HashSet::new()),
+                queue: Mutex::new(tx),
+            }),
+        };
+        let worker = index.clone();
+        let drive = drive.background();
+        thread::spawn(move || worker.revalidate(drive, dir_cache, rx));
+        Ok(index)
+    }
+
+    fn entry_path(&self, path: &str) -> PathBuf {
+        self.inner.dir.join(format!("{}.json", path_hash(path)))
+    }
+
+    /// Listing of `path` saved by an earlier run, it is put into the
+    /// directory cache and revalidated in the background. `None` once the
+    /// directory has been listed in this run.
+    pub fn get(&self, path: &str) -> Option<Vec<AlistFile>> {
+        if self.inner.fresh.lock().contains(path) {
+            return None;
+        }
+        let files = self.stale(path)?;
+        // before the revalidated listing can arrive, which must win
+        self.inner.dir_cache.insert(path.to_string(), files.clone());
+        if self.inner.fresh.lock().insert(path.to_string()) {
+            debug!(path = %path, "dir index hit, revalidate");
+            let _ = self.inner.queue.lock().send(path.to_string());
+        }
+        Some(files)
+    }
+
+    /// Last listing of `path` saved, whether from this run or an earlier
+    /// one, for when the server can't be reached
+    pub fn stale(&self, path: &str) -> Option<Vec<AlistFile>> {
+        fs::read(self.entry_path(path))
+            .ok()
+            .and_then(|data| serde_json::from_slice::<IndexEntry>(&data).ok())
+            .filter(|entry| entry.path == path)
+            .map(|entry| entry.files)
+    }
+
+    /// Whether `get` would return a listing
+    pub fn contains(&self, path: &str) -> bool {
+        !self.inner.fresh.lock().contains(path) && self.entry_path(path).exists()
+    }
+
+    pub fn save(&self, path: &str, files: &[AlistFile]) {
+        self.inner.fresh.lock().insert(path.to_string());
+        // subdirectories that are gone take their listings with them
+        if let Some(old) = self.stale(path) {
+            for dir in old.iter().filter(|file| file.file.is_dir) {
+                if !files.iter().any(|file| file.file.is_dir && file.path == dir.path) {
+                    self.remove_tree(&dir.path);
+                }
+            }
+        }
+        let entry = IndexEntry {
+            path: path.to_string(),
+            files: files.to_vec(),
+        };
+        let file = self.entry_path(path);
+        let tmp = file.with_extension("tmp");
+        let res = serde_json::to_vec(&entry)
+            .map_err(std::io::Error::from)
+            .and_then(|data| fs::write(&tmp, data))
+            .and_then(|_| fs::rename(&tmp, &file));
+        if let Err(err) = res {
+            error!(path = %path, error = %err, "save dir index failed");
+        }
+    }
+
+    /// Forget the listing of a directory that changed
+    pub fn remove(&self, path: &str) {
+        self.inner.fresh.lock().insert(path.to_string());
+        let _ = fs::remove_file(self.entry_path(path));
+    }
+
+    /// Forget the listings of a removed or moved directory and below
+    pub fn remove_tree(&self, path: &str) {
+        let children = self.stale(path).unwrap_or_default();
+        self.remove(path);
+        for dir in children.iter().filter(|file| file.file.is_dir) {
+            debug!(path = %dir.path, "drop dir index entry");
+            self.remove_tree(&dir.path);
+        }
+    }
+
+    fn revalidate(&self, drive: AlistDrive, dir_cache: Cache, rx: Receiver<String>) {
+        let mut count = 0;
+        while let Ok(path) = rx.recv() {
+            match drive.list_all(&path) {
+                Ok(files) => {
+                    self.save(&path, &files);
+                    dir_cache.insert(path, files);
+                    count += 1;
+                    if count % 100 == 0 {
+                        info!(count = count, "dir index revalidated");
+                    }
+                }
+                Err(err) => {
+                    // the indexed listing stays in the directory cache, better
+                    // stale than nothing while the server is unreachable
+                    warn!(path = %path, error = %err, "revalidate dir index failed, serving stale listing");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,198 @@
+//! Client side throttling of alist API calls
+//!
+//! Every `/api/fs/list` and `/api/fs/get` call takes a permit from a token
+//! bucket before it is sent, interactive lookups and background work are
+//! charged against separate budgets so a prefetcher can't starve `ls`.
+use std::time::{Duration, Instant};
+
+use parking_lot::{Condvar, Mutex};
+use tracing::{debug, warn};
+
+/// Lowest fraction of the configured rate we fall back to after 429s
+const MIN_RATE_FACTOR: f64 = 0.05;
+/// How much of the configured rate is restored per second without 429s
+const RATE_RECOVERY_PER_SEC: f64 = 0.02;
+
+/// Which budget an API call is charged against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Calls a FUSE request is blocked on
+    Interactive,
+    /// Prefetching, revalidation and other work nobody is waiting for
+    Background,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitConfig {
+    /// Requests per second for interactive calls, 0 means unlimited
+    pub interactive_qps: f64,
+    /// Max in-flight interactive calls, 0 means unlimited
+    pub interactive_concurrency: usize,
+    /// Requests per second for background calls, 0 means unlimited
+    pub background_qps: f64,
+    /// Max in-flight background calls, 0 means unlimited
+    pub background_concurrency: usize,
+}
+
+#[derive(Debug)]
+struct Budget {
+    qps: f64,
+    max_in_flight: usize,
+    tokens: f64,
+    last_refill: Instant,
+    in_flight: usize,
+}
+
+impl Budget {
+    fn new(qps: f64, max_in_flight: usize) -> Self {
+        Self {
+            qps,
+            max_in_flight,
+            tokens: qps.max(1.0),
+            last_refill: Instant::now(),
+            in_flight: 0,
+        }
+    }
+
+    fn refill(&mut self, now: Instant, factor: f64) {
+        if self.qps <= 0.0 {
+            return;
+        }
+        let rate = self.qps * factor;
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        // allow a burst of at most one second worth of requests
+        self.tokens = (self.tokens + elapsed * rate).min(rate.max(1.0));
+        self.last_refill = now;
+    }
+
+    fn has_slot(&self) -> bool {
+        self.max_in_flight == 0 || self.in_flight < self.max_in_flight
+    }
+
+    /// How long until a token is available, `None` if one is available now
+    fn wait_time(&self, factor: f64) -> Option<Duration> {
+        if self.qps <= 0.0 || self.tokens >= 1.0 {
+            return None;
+        }
+        let rate = self.qps * factor;
+        Some(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+    }
+
+    fn take(&mut self) {
+        if self.qps > 0.0 {
+            self.tokens -= 1.0;
+        }
+        self.in_flight += 1;
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    interactive: Budget,
+    background: Budget,
+    /// Fraction of the configured rates currently in effect
+    factor: f64,
+    factor_updated: Instant,
+}
+
+impl State {
+    fn budget_mut(&mut self, priority: Priority) -> &mut Budget {
+        match priority {
+            Priority::Interactive => &mut self.interactive,
+            Priority::Background => &mut self.background,
+        }
+    }
+
+    fn recover(&mut self, now: Instant) {
+        if self.factor < 1.0 {
+            let elapsed = now.duration_since(self.factor_updated).as_secs_f64();
+            self.factor = (self.factor + elapsed * RATE_RECOVERY_PER_SEC).min(1.0);
+        }
+        self.factor_updated = now;
+    }
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        let state = State {
+            interactive: Budget::new(config.interactive_qps, config.interactive_concurrency),
+            background: Budget::new(config.background_qps, config.background_concurrency),
+            factor: 1.0,
+            factor_updated: Instant::now(),
+        };
+        Self {
+            state: Mutex::new(state),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Block until a request of the given priority may be sent
+    pub fn acquire(&self, priority: Priority) -> Permit<'_> {
+        let mut state = self.state.lock();
+        loop {
+            let now = Instant::now();
+            state.recover(now);
+            let factor = state.factor;
+            let budget = state.budget_mut(priority);
+            budget.refill(now, factor);
+            let wait = if budget.has_slot() {
+                match budget.wait_time(factor) {
+                    None => {
+                        budget.take();
+                        return Permit {
+                            limiter: self,
+                            priority,
+                        };
+                    }
+                    Some(wait) => Some(wait),
+                }
+            } else {
+                None
+            };
+            match wait {
+                Some(wait) => {
+                    debug!(?priority, wait_ms = wait.as_millis() as u64, "rate limited");
+                    self.cond.wait_for(&mut state, wait);
+                }
+                None => {
+                    debug!(?priority, "too many requests in flight");
+                    self.cond.wait(&mut state);
+                }
+            }
+        }
+    }
+
+    /// The server answered 429, back off
+    pub fn throttled(&self) {
+        let mut state = self.state.lock();
+        state.recover(Instant::now());
+        state.factor = (state.factor / 2.0).max(MIN_RATE_FACTOR);
+        warn!(rate_factor = state.factor, "throttled by server, lowering request rate");
+    }
+
+    fn release(&self, priority: Priority) {
+        let mut state = self.state.lock();
+        let budget = state.budget_mut(priority);
+        budget.in_flight = budget.in_flight.saturating_sub(1);
+        self.cond.notify_all();
+    }
+}
+
+/// An in-flight request slot, released on drop
+#[derive(Debug)]
+pub struct Permit<'a> {
+    limiter: &'a RateLimiter,
+    priority: Priority,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.limiter.release(self.priority);
+    }
+}
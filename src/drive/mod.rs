@@ -1,40 +1,37 @@
 use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::thread;
-use std::time::{Duration,SystemTime, UNIX_EPOCH};
+use std::time::Duration;
 
 use anyhow::{bail, Context, Result};
-use bytes::Bytes;
 use parking_lot::RwLock;
 use reqwest::{
-    header::{HeaderMap, HeaderValue},
+    blocking::Response,
+    header::HeaderMap,
     StatusCode,
 };
-use url::form_urlencoded;
 use serde::de::DeserializeOwned;
-use quick_xml::de::from_str;
-use quick_xml::Writer;
-use quick_xml::se::Serializer as XmlSerializer;
 use serde_json::{json, Value};
 use serde::{Serialize,Deserialize};
 use tracing::{debug, error, info, warn};
-use httpdate;
-use hmacsha::HmacSha;
-use sha1::{Sha1};
 use sha256::digest;
-use hex_literal::hex;
-use base64::encode;
 
 
 
 
+mod limiter;
 pub mod model;
 
+pub use limiter::{Priority, RateLimitConfig, RateLimiter};
 pub use model::*;
 pub use model::{AlistFile, DateTime, FileType};
 
+/// An upload may take this long plus a second for every `UPLOAD_MIN_SPEED` bytes
+const UPLOAD_BASE_TIMEOUT: Duration = Duration::from_secs(300);
+const UPLOAD_MIN_SPEED: u64 = 256 * 1024;
+
 const UA: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/92.0.4515.131 Safari/537.36";
 
 #[derive(Debug, Clone)]
@@ -42,6 +39,7 @@ pub struct DriveConfig {
     pub api_base_url: String,
     pub refresh_token_url: String,
     pub workdir: Option<PathBuf>,
+    pub rate_limit: RateLimitConfig,
 }
 
 
@@ -54,6 +52,8 @@ pub struct AlistDrive {
     credentials: Arc<RwLock<Credentials>>,
     drive_id: Option<String>,
     pub nick_name: Option<String>,
+    limiter: Arc<RateLimiter>,
+    priority: Priority,
 }
 
 impl AlistDrive {
@@ -74,12 +74,15 @@ impl AlistDrive {
             .connect_timeout(Duration::from_secs(10))
             .timeout(Duration::from_secs(30))
             .build()?;
+        let limiter = Arc::new(RateLimiter::new(config.rate_limit));
         let mut drive = Self {
             config,
             client,
             credentials: Arc::new(RwLock::new(credentials)),
             drive_id: None,
             nick_name: None,
+            limiter,
+            priority: Priority::Interactive,
         };
 
         let (tx, rx) = oneshot::channel();
@@ -124,6 +127,15 @@ impl AlistDrive {
         Ok(drive)
     }
 
+    /// A handle to the same drive whose API calls are charged against the
+    /// background budget of the rate limiter
+    pub fn background(&self) -> Self {
+        Self {
+            priority: Priority::Background,
+            ..self.clone()
+        }
+    }
+
     fn save_refresh_token(&self, refresh_token: &str) -> Result<()> {
         if let Some(dir) = self.config.workdir.as_ref() {
             fs::create_dir_all(dir)?;
@@ -330,7 +342,25 @@ impl AlistDrive {
         T: Serialize + ?Sized,
         U: DeserializeOwned,
     {
+        self.send_post(url, req, false)
+    }
+
+    /// `post_request` counted against the rate limit, a retry waits for a
+    /// permit of its own
+    fn limited_post_request<T, U>(&self, url: String, req: &T) -> Result<Option<U>>
+    where
+        T: Serialize + ?Sized,
+        U: DeserializeOwned,
+    {
+        self.send_post(url, req, true)
+    }
 
+    fn send_post<T, U>(&self, url: String, req: &T, limited: bool) -> Result<Option<U>>
+    where
+        T: Serialize + ?Sized,
+        U: DeserializeOwned,
+    {
+        let mut permit = limited.then(|| self.limiter.acquire(self.priority));
         let mut access_token = self.access_token()?;
         let mut headers = reqwest::header::HeaderMap::new();
         headers.insert("Accept", "application/json, text/plain, */*".parse()?);
@@ -379,9 +409,17 @@ impl AlistDrive {
                             let token_res = self.do_refresh_token_with_retry(None)?;
                             access_token = token_res.data.token;
                         } else {
+                            if status_code == StatusCode::TOO_MANY_REQUESTS {
+                                self.limiter.throttled();
+                            }
                             // wait for a while and retry
                             thread::sleep(Duration::from_secs(1));
                         }
+                        // the retry is another request, at the rate lowered above
+                        let _permit = permit.take().map(|permit| {
+                            drop(permit);
+                            self.limiter.acquire(self.priority)
+                        });
                         let res = self
                             .client
                             .post(url)
@@ -504,7 +542,7 @@ impl AlistDrive {
             refresh:false,
         };
         let mut rurl = format!("{}/api/fs/list",self.config.api_base_url);
-        self.post_request(rurl, &list_req).and_then(|res: Option<ListFileResponse>| res.context("expect response"))
+        self.limited_post_request(rurl, &list_req).and_then(|res: Option<ListFileResponse>| res.context("expect response"))
     }
 
 
@@ -545,158 +583,12 @@ impl AlistDrive {
         self.post_request(rurl,&req).and_then(|res| res.context("expect response"))
     }
 
-    pub fn create_file_with_proof(&self,name: &str, parent_file_id: &str, hash:&str, size: u64) ->  Result<UploadResponse> {
-        debug!("drive create file with proof {}", name);
-        let url = format!("{}",self.config.api_base_url);
-        let req = UploadRequest{
-            kind:"drive#file".to_string(),
-		    name:name.to_string(),
-		    size:size,
-		    hash: hash.to_string(),
-		    upload_type: "UPLOAD_TYPE_RESUMABLE".to_string(),
-            objProvider: ObjProvider { provider: "UPLOAD_TYPE_UNKNOWN".to_string() },
-		    parent_id:parent_file_id.to_string(),
-        };
-        let payload = serde_json::to_string(&req).unwrap();
-        let access_token_key = "access_token".to_string();
-        let access_token = self.access_token().unwrap();
-
-        let res = self.client.post(url)
-            .header(reqwest::header::CONTENT_LENGTH, payload.len())
-            .header(reqwest::header::HOST, "api-drive.myalist.com")
-            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}",access_token))
-            .body(payload)
-            .send();
-
-        let body = match res {
-            Ok(res) => res.text().unwrap(),
-            Err(err) => {
-                error!("{:?}", err);
-                return Err(err.into());
-            }
-        };
-            
-        let result = match serde_json::from_str::<UploadResponse>(&body) {
-            Ok(result) => result,
-            Err(e) => {
-                error!(error = %e, "create_file_with_proof");
-                return Err(e.into());
-            }
-        };
-    
-        Ok(result)
-    }
-
-
-    pub fn get_pre_upload_info(&self,oss_args:&OssArgs) -> Result<String> {
-        let mut url = format!("https://{}/{}?uploads",oss_args.endpoint,oss_args.key);
-        let now = SystemTime::now();
-        let gmt = httpdate::fmt_http_date(now);
-        let mut req = self.client.post(url)
-            .header(reqwest::header::USER_AGENT, "aliyun-sdk-android/2.9.5(Linux/Android 11/ONEPLUS%20A6000;RKQ1.201217.002)")
-            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
-            .header("X-Oss-Security-Token", &oss_args.security_token)
-            .header("Date", &gmt).build()?;
-        let oss_sign:String = self.hmac_authorization(&req,&gmt,oss_args);
-        let oss_header = format!("OSS {}:{}",&oss_args.access_key_id,&oss_sign);
-        let header_auth = HeaderValue::from_str(&oss_header).unwrap();
-        req.headers_mut().insert(reqwest::header::AUTHORIZATION, header_auth);
-        let res = self.client.execute(req);
-        let body = match res {
-            Ok(res) => res.text().unwrap(),
-            Err(err) => {
-                error!("{:?}", err);
-                return Err(err.into());
-            }
-        };
-        let result: InitiateMultipartUploadResult = from_str(&body).unwrap();
-        Ok(result.UploadId.clone())
-    }
-
-    pub fn upload_chunk(&self, file:&AlistFile, oss_args:&OssArgs, upload_id:&str, current_chunk:u64,body: Bytes) -> Result<(PartInfo)> {
-        debug!(file_name=%file.file.name,upload_id = upload_id,current_chunk=current_chunk, "upload_chunk");
-        let encoded: String = form_urlencoded::Serializer::new(String::new())
-        .append_pair("partNumber", current_chunk.to_string().as_str())
-        .append_pair("uploadId", upload_id)
-        .finish();
-
-        let url = format!("https://{}/{}?{}",oss_args.endpoint,oss_args.key,encoded);
-   
-        let now = SystemTime::now();
-        let gmt = httpdate::fmt_http_date(now);
-        let mut req = self.client.put(url)
-            .body(body)
-            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
-            .header("X-Oss-Security-Token", &oss_args.security_token)
-            .header("Date", &gmt).build()?;
-        let oss_sign:String = self.hmac_authorization(&req,&gmt,oss_args);
-        let oss_header = format!("OSS {}:{}",&oss_args.access_key_id,&oss_sign);
-        let header_auth = HeaderValue::from_str(&oss_header).unwrap();
-        req.headers_mut().insert(reqwest::header::AUTHORIZATION, header_auth);
-        let res = self.client.execute(req);
-        // let body = match res {
-        //     Ok(res) => res.text().unwrap(),
-        //     Err(err) => {
-        //         error!("{:?}", err);
-        //         return Err(err.into());
-        //     }
-        // };
-        //let body = &res.text().await?;
-
-        let etag  = match &res.unwrap().headers().get("ETag") {
-            Some(etag) => etag.to_str().unwrap().to_string(),
-            None => "".to_string(),
-        };
-            
-        let part = PartInfo {
-            PartNumber: PartNumber { PartNumber: current_chunk },
-            ETag: etag,
-        };
-        
-        Ok(part)
-    }
-
-    pub fn complete_upload(&self,file:&AlistFile, upload_tags:String, oss_args:&OssArgs, upload_id:&str)-> Result<()> {
-        debug!(file = %file.file.name, "complete_upload");
-        let url = format!("https://{}/{}?uploadId={}",oss_args.endpoint,oss_args.key,upload_id);
-        let now = SystemTime::now();
-        let gmt = httpdate::fmt_http_date(now);
-        let mut req = self.client.post(url)
-            .body(upload_tags)
-            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
-            .header("X-Oss-Security-Token", &oss_args.security_token)
-            .header("Date", &gmt).build()?;
-        let oss_sign:String = self.hmac_authorization(&req,&gmt,oss_args);
-        let oss_header = format!("OSS {}:{}",&oss_args.access_key_id,&oss_sign);
-        let header_auth = HeaderValue::from_str(&oss_header).unwrap();
-        req.headers_mut().insert(reqwest::header::AUTHORIZATION, header_auth);
-        let res = self.client.execute(req);
-
-        let body = match res {
-            Ok(res) => res.text().unwrap(),
-            Err(err) => {
-                error!("{:?}", err);
-                return Err(err.into());
-            }
-        };
-        debug!(file = %file.file.name, res_body = body, "complete_upload_response");
-        Ok(())
-    }
-
-    pub fn hmac_authorization(&self, req:&reqwest::blocking::Request,time:&str,oss_args:&OssArgs)->String{
-        let message = format!("{}\n\n{}\n{}\nx-oss-security-token:{}\n/{}{}?{}",req.method().as_str(),req.headers().get(reqwest::header::CONTENT_TYPE).unwrap().to_str().unwrap(),time,oss_args.security_token,oss_args.bucket,req.url().path(),req.url().query().unwrap());
-        let key = &oss_args.access_key_secret;
-      
-        let mut hasher = HmacSha::from(key, &message, Sha1::default());
-        let result = hasher.compute_digest();
-        let signature_base64 = base64::encode(&result);
-        signature_base64
-    }
-
-
-
-    pub fn download(&self, url: &str, start_pos: u64, size: usize) -> Result<Bytes> {
-        debug!(url = %url, "download file");
+    /// Start a ranged download, the body is streamed by the caller.
+    ///
+    /// No per-request deadline is set, the client timeout applies to the
+    /// response headers and to each read of the body separately, so slow but
+    /// steady transfers are never cut off.
+    pub fn download(&self, url: &str, start_pos: u64, size: usize) -> Result<Response> {
         use reqwest::header::RANGE;
         let end_pos = start_pos + size as u64 - 1;
         debug!(url = %url, start = start_pos, end = end_pos, "download file");
@@ -707,7 +599,11 @@ impl AlistDrive {
             .header(RANGE, range)
             .send()?
             .error_for_status()?;
-        Ok(res.bytes()?)
+        // a server ignoring the range sends the file from its start
+        if res.status() != StatusCode::PARTIAL_CONTENT {
+            bail!("range request answered with {}", res.status());
+        }
+        Ok(res)
     }
 
     pub fn get_download_url(&self, file_id: &str) -> Result<String> {
@@ -717,7 +613,7 @@ impl AlistDrive {
             password:"",
         };
         let mut rurl = format!("{}/api/fs/get",self.config.api_base_url);
-        let res = self.post_request(rurl, &list_req).and_then(|res: Option<GetFileDownloadUrlResponse>| res.context("expect response"));
+        let res = self.limited_post_request(rurl, &list_req).and_then(|res: Option<GetFileDownloadUrlResponse>| res.context("expect response"));
         let download_url = match res {
             Ok(res) => res.data.raw_url,
             Err(err) => "".to_string()
@@ -725,13 +621,110 @@ impl AlistDrive {
         Ok(download_url)
     }
 
-    pub fn get_quota(&self) -> Result<(u64, u64)> {
-        let drive_id = self.drive_id()?;
-        let mut data = HashMap::new();
-        data.insert("drive_id", drive_id);
-        let res: GetDriveResponse = self
-            .request(format!("{}/v2/drive/get", self.config.api_base_url), &data)?
+    /// Look up a single file or directory without listing its parent,
+    /// `None` if it doesn't exist
+    pub fn get_file(&self, path: &str) -> Result<Option<AlistFile>> {
+        debug!(path = %path, "get file");
+        let req = GetFileDownloadUrlRequest {
+            path,
+            password: "",
+        };
+        let rurl = format!("{}/api/fs/get", self.config.api_base_url);
+        let res: GetFileResponse = self
+            .limited_post_request(rurl, &req)?
             .context("expect response")?;
-        Ok((res.used_size, res.total_size))
+        match res.data {
+            Some(file) if res.code == 200 => Ok(Some(AlistFile {
+                path: path.to_string(),
+                file: file.into(),
+            })),
+            _ if res.message.contains("not found") => Ok(None),
+            _ => bail!("get file {} failed: {}", path, res.message),
+        }
+    }
+
+    /// Used and total bytes summed over the storages that report their
+    /// capacity, listing storages needs an admin account
+    pub fn get_quota(&self) -> Result<(u64, u64)> {
+        let url = format!("{}/api/admin/storage/list", self.config.api_base_url);
+        let _permit = self.limiter.acquire(self.priority);
+        let mut res = self.get_storage_list(&url, self.access_token()?)?;
+        if res.code == 401 {
+            let token_res = self.do_refresh_token_with_retry(None)?;
+            res = self.get_storage_list(&url, token_res.data.token)?;
+        }
+        let storages = match res.data {
+            Some(data) if res.code == 200 => data.content,
+            _ => bail!("list storages failed: {}", res.message),
+        };
+        let (mut used, mut total) = (0, 0);
+        for storage in storages.iter().filter(|storage| !storage.disabled) {
+            if let Some(details) = storage.mount_details.as_ref().filter(|d| d.total_space > 0) {
+                debug!(mount_path = %storage.mount_path, total = details.total_space, free = details.free_space, "storage capacity");
+                total += details.total_space;
+                used += details.total_space.saturating_sub(details.free_space);
+            }
+        }
+        if total == 0 {
+            bail!("no storage reports its capacity");
+        }
+        Ok((used, total))
+    }
+
+    /// Upload the content of the local file `src` to `path`, replacing the
+    /// file at `path` if there is one
+    pub fn put_file(&self, path: &str, src: &Path) -> Result<()> {
+        let url = format!("{}/api/fs/put", self.config.api_base_url);
+        let mut res = self.put_request(&url, path, src, self.access_token()?)?;
+        if res.code == 401 {
+            let token_res = self.do_refresh_token_with_retry(None)?;
+            res = self.put_request(&url, path, src, token_res.data.token)?;
+        }
+        if res.code != 200 {
+            bail!("upload {} failed: {}", path, res.message);
+        }
+        Ok(())
+    }
+
+    fn put_request(&self, url: &str, path: &str, src: &Path, access_token: String) -> Result<PutFileResponse> {
+        let file = fs::File::open(src)?;
+        let size = file.metadata()?.len();
+        debug!(path = %path, size = size, "put file");
+        // alist path-unescapes this header, so `+` has to stay encoded too
+        let file_path: String = path
+            .bytes()
+            .map(|b| match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                    (b as char).to_string()
+                }
+                _ => format!("%{:02X}", b),
+            })
+            .collect();
+        let timeout = UPLOAD_BASE_TIMEOUT + Duration::from_secs(size / UPLOAD_MIN_SPEED);
+        let res = self
+            .client
+            .put(url)
+            .header("Authorization", access_token)
+            .header("File-Path", file_path)
+            .header("Overwrite", "true")
+            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+            .header(reqwest::header::CONTENT_LENGTH, size)
+            .timeout(timeout)
+            .body(reqwest::blocking::Body::sized(file, size))
+            .send()?
+            .error_for_status()?
+            .json::<PutFileResponse>()?;
+        Ok(res)
+    }
+
+    fn get_storage_list(&self, url: &str, access_token: String) -> Result<StorageListResponse> {
+        let res = self
+            .client
+            .get(url)
+            .header("Authorization", access_token)
+            .send()?
+            .error_for_status()?
+            .json::<StorageListResponse>()?;
+        Ok(res)
     }
 }
@@ -101,9 +101,61 @@ pub struct DownloadFile {
 
 
 #[derive(Debug, Clone, Deserialize)]
-pub struct GetDriveResponse {
-    pub total_size: u64,
-    pub used_size: u64,
+pub struct GetFileResponse {
+    pub code: u64,
+    pub message: String,
+    pub data: Option<DownloadFile>,
+}
+
+impl From<DownloadFile> for ResFile {
+    fn from(file: DownloadFile) -> Self {
+        Self {
+            name: file.name,
+            size: file.size,
+            is_dir: file.is_dir,
+            created: file.created,
+            modified: file.modified,
+            sign: file.sign,
+            thumb: file.thumb,
+            hashinfo: file.hashinfo,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PutFileResponse {
+    pub code: u64,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StorageListResponse {
+    pub code: u64,
+    pub message: String,
+    pub data: Option<StorageList>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StorageList {
+    pub content: Vec<Storage>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Storage {
+    pub mount_path: String,
+    #[serde(default)]
+    pub disabled: bool,
+    /// Only filled in by drivers that know their capacity
+    #[serde(default)]
+    pub mount_details: Option<StorageDetails>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StorageDetails {
+    #[serde(default)]
+    pub total_space: u64,
+    #[serde(default)]
+    pub free_space: u64,
 }
 
 
@@ -194,90 +246,6 @@ pub struct RenameFileRequest<'a>{
 }
 
 
-#[derive(Debug, Clone,Serialize, Deserialize)]
-pub struct UploadRequest {
-    pub kind: String,
-    pub name: String,
-    pub size: u64,
-    pub hash: String,
-    pub upload_type: String,
-    pub objProvider:ObjProvider,
-    pub parent_id: String,
-}
-
-#[derive(Debug, Clone,Serialize, Deserialize)]
-pub struct ObjProvider {
-    pub provider: String,
-}
-
-#[derive(Debug, Clone,Serialize, Deserialize)]
-pub struct OssArgs {
-    pub bucket: String,
-    pub endpoint: String,
-    pub access_key_id: String,
-    pub access_key_secret: String,
-    pub key: String,
-    pub security_token: String,
-}
-
-
-#[derive(Debug, Clone,Serialize, Deserialize)]
-pub struct CompleteMultipartUpload {
-    pub Part: Vec<PartInfo>,
-}
-
-#[derive(Debug, Clone,Serialize, Deserialize)]
-pub struct PartInfo {
-    #[serde(flatten)]
-    pub PartNumber: PartNumber,
-    pub ETag: String,
-}
-
-#[derive(Debug, Clone,Serialize, Deserialize)]
-pub struct PartNumber {
-    pub PartNumber: u64,
-}
-
-
-
-
-#[derive(Debug, Clone,Serialize, Deserialize)]
-pub struct UploadResponse {
-    pub upload_type: String,
-    pub resumable: Resumable,
-    pub file: AlistFile,
-}
-
-
-#[derive(Debug, Clone,Serialize, Deserialize)]
-pub struct Resumable {
-    pub kind: String,
-    pub provider: String,
-    pub params: UploadParams,
-}
-
-#[derive(Debug, Clone,Serialize, Deserialize)]
-pub struct UploadParams {
-    pub access_key_id: String,
-    pub access_key_secret: String,
-    pub bucket: String,
-    pub endpoint: String,
-    pub expiration: String,
-    pub key: String,
-    pub security_token: String,
-}
-
-#[derive(Debug, Deserialize, PartialEq)]
-pub struct InitiateMultipartUploadResult {
-    pub Bucket: String,
-    pub Key: String,
-    pub UploadId: String,
-}
-
-
-
-
-
 impl AlistFile {
     pub fn new_root() -> Self {
         let now = SystemTime::now();
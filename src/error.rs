@@ -7,7 +7,12 @@ pub enum Error {
     ChildNotFound,
     ApiCallFailed,
     UploadFailed,
+    SpoolFailed,
     NotFound,
+    NotSupported,
+    NoAttribute,
+    InvalidArgument,
+    ReadOnly,
 }
 
 impl From<Error> for c_int {
@@ -19,6 +24,14 @@ impl From<Error> for c_int {
             Error::NotFound => libc::ENOENT,
             Error::ApiCallFailed => libc::EIO,
             Error::UploadFailed => libc::EIO,
+            Error::SpoolFailed => libc::EIO,
+            Error::NotSupported => libc::ENOTSUP,
+            #[cfg(target_os = "macos")]
+            Error::NoAttribute => libc::ENOATTR,
+            #[cfg(not(target_os = "macos"))]
+            Error::NoAttribute => libc::ENODATA,
+            Error::InvalidArgument => libc::EINVAL,
+            Error::ReadOnly => libc::EROFS,
         }
     }
 }
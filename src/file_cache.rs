@@ -1,100 +1,753 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Instant, SystemTime};
 
-use bytes::Bytes;
-use tracing::debug;
+use bytes::{Bytes, BytesMut};
+use parking_lot::{Condvar, Mutex};
+use tracing::{debug, error, info};
 
+use crate::block_cache::{BlockCache, FileBlocks, BLOCK_SIZE};
+use crate::drive::AlistFile;
 use crate::error::Error;
+use crate::pin::Pinner;
+use crate::probe_cache::{FileProbe, ProbeCache};
 use crate::AlistDrive;
 
+/// Size of each read from the response body
+const STREAM_READ_SIZE: usize = 64 * 1024;
+/// Don't split a chunk into ranges smaller than this
+const MIN_PART_SIZE: usize = 1024 * 1024;
+
+/// One HTTP range request filling part of a chunk
+#[derive(Debug)]
+struct ChunkPart {
+    start: usize,
+    end: usize,
+    filled: usize,
+    done: bool,
+    failed: bool,
+}
+
+#[derive(Debug)]
+struct ChunkState {
+    buffer: Vec<u8>,
+    // sorted and contiguous
+    parts: Vec<ChunkPart>,
+    cancelled: bool,
+}
+
+impl ChunkState {
+    /// The first part that hasn't been filled at or after `pos`
+    fn pending_part(&self, pos: usize) -> Option<&ChunkPart> {
+        self.parts
+            .iter()
+            .find(|part| part.end > pos && part.start + part.filled < part.end)
+    }
+}
+
+/// Local copies of a file that outlive its open handles
+#[derive(Debug, Clone, Default)]
+struct Persisted {
+    blocks: Option<FileBlocks>,
+    probe: Option<FileProbe>,
+}
+
+impl Persisted {
+    fn read(&self, offset: u64, size: usize) -> Option<Bytes> {
+        self.probe
+            .as_ref()
+            .and_then(|probe| probe.read(offset, size))
+            .or_else(|| self.blocks.as_ref().and_then(|blocks| blocks.read(offset, size)))
+    }
+
+    fn save(&self, offset: u64, data: &[u8]) {
+        if let Some(probe) = self.probe.as_ref() {
+            probe.save(offset, data);
+        }
+        if let Some(blocks) = self.blocks.as_ref() {
+            blocks.write(offset, data);
+        }
+    }
+}
+
+/// A range of a file that is downloaded in the background, possibly over
+/// several connections, reads are answered as soon as the bytes they need
+/// have arrived
+#[derive(Debug)]
+struct Chunk {
+    start_pos: i64,
+    size: usize,
+    state: Mutex<ChunkState>,
+    cond: Condvar,
+    /// Where to persist the chunk once it's complete
+    persisted: Persisted,
+    /// Tick of the last read served from the chunk
+    last_access: AtomicU64,
+}
+
+impl Chunk {
+    fn spawn(
+        drive: AlistDrive,
+        file_id: String,
+        start_pos: i64,
+        size: usize,
+        tuner: Option<Arc<ConnectionTuner>>,
+        persisted: Persisted,
+    ) -> Arc<Self> {
+        let connections = tuner
+            .as_ref()
+            .map(|tuner| tuner.connections())
+            .unwrap_or(1)
+            .min(size / MIN_PART_SIZE)
+            .max(1);
+        let part_size = size.div_ceil(connections);
+        let parts = (0..size)
+            .step_by(part_size.max(1))
+            .map(|start| ChunkPart {
+                start,
+                end: (start + part_size).min(size),
+                filled: 0,
+                done: false,
+                failed: false,
+            })
+            .collect();
+        let chunk = Arc::new(Self {
+            start_pos,
+            size,
+            state: Mutex::new(ChunkState {
+                buffer: vec![0; size],
+                parts,
+                cancelled: false,
+            }),
+            cond: Condvar::new(),
+            persisted,
+            last_access: AtomicU64::new(0),
+        });
+        let filler = chunk.clone();
+        thread::spawn(move || filler.fill(&drive, &file_id, tuner.as_deref()));
+        chunk
+    }
+
+    fn fill(self: &Arc<Self>, drive: &AlistDrive, file_id: &str, tuner: Option<&ConnectionTuner>) {
+        let parts = self.state.lock().parts.len();
+        if parts == 0 {
+            // nothing to download past the end of the file
+            return;
+        }
+        let url = match drive.get_download_url(file_id) {
+            Ok(url) => url,
+            Err(err) => {
+                error!(file_id = %file_id, error = %err, "get download url failed");
+                for index in 0..parts {
+                    self.finish_part(index, true);
+                }
+                return;
+            }
+        };
+        let started = Instant::now();
+        let handles: Vec<_> = (1..parts)
+            .map(|index| {
+                let chunk = self.clone();
+                let drive = drive.clone();
+                let url = url.clone();
+                thread::spawn(move || chunk.fill_part(&drive, &url, index))
+            })
+            .collect();
+        let mut ok = self.fill_part(drive, &url, 0);
+        for handle in handles {
+            ok &= handle.join().unwrap_or(false);
+        }
+        if let Some(tuner) = tuner.filter(|_| ok) {
+            tuner.record(parts, self.size, started.elapsed().as_secs_f64());
+        }
+        // keep what arrived even if the download was cancelled or failed half way
+        let state = self.state.lock();
+        let available = state
+            .pending_part(0)
+            .map(|part| part.start + part.filled)
+            .unwrap_or(self.size);
+        self.persisted.save(self.start_pos as u64, &state.buffer[..available]);
+    }
+
+    /// Download one part, returns whether it completed
+    fn fill_part(&self, drive: &AlistDrive, url: &str, index: usize) -> bool {
+        let (start, end) = {
+            let state = self.state.lock();
+            (state.parts[index].start, state.parts[index].end)
+        };
+        let failed = match drive.download(url, self.start_pos as u64 + start as u64, end - start) {
+            Ok(mut res) => {
+                let mut buf = vec![0u8; STREAM_READ_SIZE];
+                loop {
+                    match res.read(&mut buf) {
+                        // the body ended before the whole part arrived
+                        Ok(0) => break self.state.lock().parts[index].filled < end - start,
+                        Ok(n) => {
+                            let mut state = self.state.lock();
+                            if state.cancelled {
+                                debug!(start = self.start_pos, "chunk download cancelled");
+                                break true;
+                            }
+                            let pos = start + state.parts[index].filled;
+                            let n = n.min(end - pos);
+                            state.buffer[pos..pos + n].copy_from_slice(&buf[..n]);
+                            state.parts[index].filled += n;
+                            self.cond.notify_all();
+                            if pos + n >= end {
+                                break false;
+                            }
+                        }
+                        Err(err) => {
+                            error!(start = self.start_pos, part = index, error = %err, "read chunk failed");
+                            break true;
+                        }
+                    }
+                }
+            }
+            Err(err) => {
+                error!(start = self.start_pos, part = index, error = %err, "download chunk failed");
+                true
+            }
+        };
+        self.finish_part(index, failed);
+        !failed
+    }
+
+    fn finish_part(&self, index: usize, failed: bool) {
+        let mut state = self.state.lock();
+        state.parts[index].done = true;
+        state.parts[index].failed = failed;
+        self.cond.notify_all();
+    }
+
+    fn end_pos(&self) -> i64 {
+        self.start_pos + self.size as i64
+    }
+
+    fn contains(&self, offset: i64) -> bool {
+        offset >= self.start_pos && offset < self.end_pos()
+    }
+
+    fn is_failed(&self) -> bool {
+        self.state.lock().parts.iter().any(|part| part.failed)
+    }
+
+    fn is_done(&self) -> bool {
+        self.state.lock().parts.iter().all(|part| part.done)
+    }
+
+    fn touch(&self, tick: u64) {
+        self.last_access.store(tick, Ordering::Relaxed);
+    }
+
+    fn cancel(&self) {
+        self.state.lock().cancelled = true;
+    }
+
+    /// Wait until `offset..offset + size` has arrived, fails if the download
+    /// ended without it. A short read would look like EOF to the kernel.
+    fn read(&self, offset: i64, size: usize) -> Result<Bytes, Error> {
+        let buf_start = (offset - self.start_pos) as usize;
+        let buf_end = buf_start + size;
+        let mut state = self.state.lock();
+        loop {
+            let (available, done, failed) = match state.pending_part(buf_start) {
+                Some(part) => (
+                    (part.start + part.filled).max(buf_start),
+                    part.done,
+                    part.failed,
+                ),
+                None => (self.size, true, false),
+            };
+            if available >= buf_end {
+                return Ok(Bytes::copy_from_slice(&state.buffer[buf_start..buf_end]));
+            }
+            if done {
+                debug!(start = self.start_pos, offset = offset, failed = failed, "chunk ended before the requested range");
+                return Err(Error::ApiCallFailed);
+            }
+            self.cond.wait(&mut state);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TunerState {
+    connections: usize,
+    last_throughput: f64,
+    increasing: bool,
+}
+
+/// Picks how many connections a chunk is downloaded over by hill climbing
+/// on the measured throughput
+#[derive(Debug)]
+struct ConnectionTuner {
+    max_connections: usize,
+    state: Mutex<TunerState>,
+}
+
+impl ConnectionTuner {
+    fn new(max_connections: usize) -> Self {
+        Self {
+            max_connections: max_connections.max(1),
+            state: Mutex::new(TunerState {
+                connections: 1,
+                last_throughput: 0.0,
+                increasing: true,
+            }),
+        }
+    }
+
+    fn connections(&self) -> usize {
+        self.state.lock().connections
+    }
+
+    fn record(&self, connections: usize, bytes: usize, secs: f64) {
+        if secs <= 0.0 || bytes < MIN_PART_SIZE {
+            return;
+        }
+        let throughput = bytes as f64 / secs;
+        let mut state = self.state.lock();
+        if connections != state.connections {
+            // measured with an outdated setting
+            return;
+        }
+        // keep going while it helps, turn around once it stops helping
+        if throughput < state.last_throughput * 0.9 {
+            state.increasing = !state.increasing;
+        }
+        state.last_throughput = throughput;
+        let next = if state.increasing {
+            (state.connections + 1).min(self.max_connections)
+        } else {
+            state.connections.saturating_sub(1).max(1)
+        };
+        if next != state.connections {
+            info!(
+                connections = next,
+                throughput = throughput as u64,
+                "adjust download connections"
+            );
+            state.connections = next;
+        }
+    }
+}
+
+/// Sequential access detection of a file handle
+#[derive(Debug, Default)]
+struct ReadAhead {
+    /// Where the last read started
+    offset: i64,
+    /// Where the next read starts if access is sequential
+    next_offset: i64,
+    /// Number of chunks to prefetch after the one being read
+    window: usize,
+}
+
+impl ReadAhead {
+    /// The part of the file this handle is reading or about to read
+    fn wanted(&self, read_buffer_size: usize) -> (i64, i64) {
+        let end = self.next_offset + (self.window * read_buffer_size) as i64;
+        (self.offset, end)
+    }
+}
+
+/// Identity of one version of a file
+type FileKey = (String, u64, SystemTime);
+
+fn file_key(file: &AlistFile) -> FileKey {
+    (file.path.clone(), file.file.size, *file.file.modified)
+}
+
+/// Cached data of a file, shared by every handle that has it open
 #[derive(Debug)]
 struct CachedFile {
     file_id: String,
     file_size: u64,
-    start_pos: i64,
-    buffer: Bytes,
+    persisted: Persisted,
+    // chunk start position -> chunk
+    chunks: BTreeMap<i64, Arc<Chunk>>,
+    // number of open handles
+    refs: usize,
+}
+
+impl CachedFile {
+    fn start_chunk(
+        &self,
+        drive: AlistDrive,
+        read_buffer_size: usize,
+        offset: i64,
+        tuner: Option<Arc<ConnectionTuner>>,
+    ) -> Arc<Chunk> {
+        let mut size = std::cmp::min(
+            read_buffer_size,
+            self.file_size.saturating_sub(offset as u64) as usize,
+        );
+        if self.persisted.blocks.is_some() {
+            // end on a block boundary so the following chunks line up with blocks
+            let end_pos = offset as u64 + size as u64;
+            let aligned = end_pos - end_pos % BLOCK_SIZE;
+            if aligned > offset as u64 && end_pos < self.file_size {
+                size = (aligned - offset as u64) as usize;
+            }
+        }
+        // don't overlap a chunk that is already there
+        if let Some((next_start, _)) = self.chunks.range(offset + 1..).next() {
+            size = size.min((next_start - offset) as usize);
+        }
+        debug!(file_id = %self.file_id, start = offset, size = size, "start chunk");
+        Chunk::spawn(
+            drive,
+            self.file_id.clone(),
+            offset,
+            size,
+            tuner,
+            self.persisted.clone(),
+        )
+    }
+
+    fn chunk_at(&self, offset: i64) -> Option<&Arc<Chunk>> {
+        self.chunks
+            .range(..=offset)
+            .next_back()
+            .map(|(_, chunk)| chunk)
+            .filter(|chunk| chunk.contains(offset) && !chunk.is_failed())
+    }
+
+    /// Cancel downloads nobody is waiting for and drop the least recently
+    /// read segments until the file fits in `budget` bytes
+    fn evict(&mut self, wanted: &[(i64, i64)], budget: usize) {
+        let is_wanted = |chunk: &Chunk| {
+            wanted
+                .iter()
+                .any(|(start, end)| chunk.start_pos < *end && chunk.end_pos() > *start)
+        };
+        self.chunks.retain(|_, chunk| {
+            let retain = !chunk.is_failed() && (chunk.is_done() || is_wanted(chunk));
+            if !retain {
+                chunk.cancel();
+            }
+            retain
+        });
+        let mut used: usize = self.chunks.values().map(|chunk| chunk.size).sum();
+        if used <= budget {
+            return;
+        }
+        let mut candidates: Vec<_> = self
+            .chunks
+            .values()
+            .filter(|chunk| !is_wanted(chunk))
+            .map(|chunk| (chunk.last_access.load(Ordering::Relaxed), chunk.start_pos))
+            .collect();
+        candidates.sort_unstable();
+        for (_, start_pos) in candidates {
+            if used <= budget {
+                break;
+            }
+            if let Some(chunk) = self.chunks.remove(&start_pos) {
+                debug!(file_id = %self.file_id, start = start_pos, "evict segment");
+                used -= chunk.size;
+            }
+        }
+    }
+}
+
+impl Drop for CachedFile {
+    fn drop(&mut self) {
+        for chunk in self.chunks.values() {
+            chunk.cancel();
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FileHandle {
+    key: FileKey,
+    read_ahead: ReadAhead,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileCacheConfig {
+    /// Read/download buffer size in bytes
+    pub read_buffer_size: usize,
+    /// Max number of chunks to prefetch for sequential reads, 0 disables read-ahead
+    pub read_ahead_chunks: usize,
+    /// Max number of concurrent connections a chunk of a sequential read is
+    /// downloaded over
+    pub download_connections: usize,
+    /// Directory of the on-disk block cache, `None` disables it
+    pub disk_cache_dir: Option<PathBuf>,
+    /// Size limit of the on-disk block cache in bytes
+    pub disk_cache_size: u64,
+    /// Memory each open handle may use to keep segments of a file around
+    pub read_cache_memory: usize,
+    /// Directory of the head/tail probe cache, `None` disables it
+    pub probe_cache_dir: Option<PathBuf>,
+    /// Bytes kept of the head and of the tail of each file
+    pub probe_cache_size: u64,
+    /// Size limit of the probe cache in bytes
+    pub probe_cache_limit: u64,
 }
 
 #[derive(Debug)]
 pub struct FileCache {
     drive: AlistDrive,
-    read_buffer_size: usize,
-    // file handle -> cached file
-    cache: BTreeMap<u64, CachedFile>,
+    config: FileCacheConfig,
+    tuner: Arc<ConnectionTuner>,
+    block_cache: Option<BlockCache>,
+    probe_cache: Option<ProbeCache>,
+    pinner: Option<Pinner>,
+    // file identity -> cached file
+    files: HashMap<FileKey, CachedFile>,
+    // file handle -> open file
+    handles: BTreeMap<u64, FileHandle>,
+    clock: u64,
 }
 
 impl FileCache {
-    pub fn new(drive: AlistDrive, read_buffer_size: usize) -> Self {
+    pub fn new(drive: AlistDrive, config: FileCacheConfig) -> Self {
+        let tuner = Arc::new(ConnectionTuner::new(config.download_connections));
+        let block_cache = config.disk_cache_dir.as_ref().and_then(|dir| {
+            BlockCache::new(dir.clone(), config.disk_cache_size)
+                .map_err(|err| error!(dir = %dir.display(), error = %err, "open block cache failed"))
+                .ok()
+        });
+        let pinner = match (&block_cache, &config.disk_cache_dir) {
+            (Some(block_cache), Some(dir)) => Some(Pinner::new(
+                drive.clone(),
+                block_cache.clone(),
+                dir.with_file_name("pins.json"),
+            )),
+            _ => None,
+        };
+        let probe_cache = config.probe_cache_dir.as_ref().and_then(|dir| {
+            ProbeCache::new(dir.clone(), config.probe_cache_size, config.probe_cache_limit)
+                .map_err(|err| error!(dir = %dir.display(), error = %err, "open probe cache failed"))
+                .ok()
+        });
         Self {
             drive,
-            read_buffer_size,
-            cache: BTreeMap::new(),
+            config,
+            tuner,
+            block_cache,
+            probe_cache,
+            pinner,
+            files: HashMap::new(),
+            handles: BTreeMap::new(),
+            clock: 0,
         }
     }
 
-    fn read_chunk(&self, file_id: &str, file_size: u64, offset: i64) -> Result<Bytes, Error> {
-        let size = std::cmp::min(
-            self.read_buffer_size,
-            file_size.saturating_sub(offset as u64) as usize,
+    /// Pinning needs the disk cache, `None` if it is disabled
+    pub fn pinner(&self) -> Option<&Pinner> {
+        self.pinner.as_ref()
+    }
+
+    fn chunk_at(&mut self, fh: u64, offset: i64) -> Result<Arc<Chunk>, Error> {
+        let handle = self.handles.get(&fh).ok_or(Error::NoEntry)?;
+        let cached = self.files.get_mut(&handle.key).ok_or(Error::NoEntry)?;
+        if let Some(chunk) = cached.chunk_at(offset) {
+            return Ok(chunk.clone());
+        }
+        // only split the download when the file is being read sequentially
+        let tuner = if handle.read_ahead.window > 0 {
+            Some(self.tuner.clone())
+        } else {
+            None
+        };
+        let chunk = cached.start_chunk(
+            self.drive.clone(),
+            self.config.read_buffer_size,
+            offset,
+            tuner,
         );
-        let download_url = self
-            .drive
-            .get_download_url(file_id)
-            .map_err(|_| Error::ApiCallFailed)?;
-        let data = self
-            .drive
-            .download(&download_url, offset as _, size)
-            .map_err(|_| Error::ApiCallFailed)?;
-        Ok(data)
+        if let Some(old) = cached.chunks.insert(chunk.start_pos, chunk.clone()) {
+            old.cancel();
+        }
+        Ok(chunk)
     }
 
-    pub fn read(&mut self, fh: u64, offset: i64, size: u32) -> Result<Bytes, Error> {
-        let cached = self.cache.get(&fh).ok_or(Error::NoEntry)?;
-        let start_pos = cached.start_pos;
+    /// Drop the chunks none of the handles of a file is going to read once
+    /// the file uses more memory than its handles are allowed
+    fn prune(&mut self, key: &FileKey) {
+        let read_buffer_size = self.config.read_buffer_size;
+        let wanted: Vec<_> = self
+            .handles
+            .values()
+            .filter(|handle| &handle.key == key)
+            .map(|handle| handle.read_ahead.wanted(read_buffer_size))
+            .collect();
+        let budget = self.config.read_cache_memory * wanted.len();
+        if let Some(cached) = self.files.get_mut(key) {
+            cached.evict(&wanted, budget);
+        }
+    }
+
+    /// Track sequential access and prefetch the chunks after `offset`
+    fn read_ahead(&mut self, fh: u64, offset: i64, size: u32) {
+        let max_window = self.config.read_ahead_chunks;
+        let read_buffer_size = self.config.read_buffer_size;
+        let handle = match self.handles.get_mut(&fh) {
+            Some(handle) => handle,
+            None => return,
+        };
         let end_pos = offset + i64::from(size);
-        let buf_size = cached.buffer.len();
-        debug!(
-            fh = fh,
-            offset = offset,
-            size = size,
-            buffer_start = start_pos,
-            buffer_size = buf_size,
-            "read file cache"
-        );
-        if offset >= start_pos && end_pos <= start_pos + buf_size as i64 {
-            let buf_start = (offset - start_pos) as usize;
-            let buf_end = buf_start + size as usize;
-            let data = cached.buffer.slice(buf_start..buf_end);
-            return Ok(data);
-        }
-        let chunk = self.read_chunk(&cached.file_id, cached.file_size, offset)?;
-        let new_cached = CachedFile {
-            file_id: cached.file_id.clone(),
-            file_size: cached.file_size,
-            start_pos: offset,
-            buffer: chunk.clone(),
+        let read_ahead = &mut handle.read_ahead;
+        let sequential = offset == read_ahead.next_offset;
+        read_ahead.offset = offset;
+        read_ahead.next_offset = end_pos;
+        let key = handle.key.clone();
+        if !sequential {
+            if read_ahead.window > 0 {
+                debug!(fh = fh, offset = offset, "seek, reset read-ahead");
+            }
+            read_ahead.window = 0;
+            self.prune(&key);
+            return;
+        }
+        if max_window == 0 {
+            self.prune(&key);
+            return;
+        }
+        let last_chunk = match self
+            .files
+            .get(&key)
+            .and_then(|cached| cached.chunk_at(end_pos - 1))
+        {
+            Some(chunk) => chunk.clone(),
+            None => return,
         };
-        self.cache.insert(fh, new_cached);
+        // grow the window every time playback moves on to the next chunk
+        if read_ahead.window == 0 || last_chunk.start_pos >= offset {
+            read_ahead.window = (read_ahead.window * 2).clamp(1, max_window);
+        }
+        let window = read_ahead.window;
+        // chunks behind us won't be read again
+        self.prune(&key);
 
-        // chunk size maybe less than size
-        let size = if chunk.len() >= size as usize {
-            size as usize
-        } else {
-            chunk.len()
+        let cached = match self.files.get_mut(&key) {
+            Some(cached) => cached,
+            None => return,
         };
-        Ok(chunk.slice(..size as usize))
+        let drive = self.drive.background();
+        let tuner = &self.tuner;
+        let mut pos = last_chunk.end_pos();
+        let limit = (cached.file_size as i64).min(pos + (window * read_buffer_size) as i64);
+        while pos < limit {
+            if let Some(blocks) = cached.persisted.blocks.as_ref() {
+                if blocks.contains(pos as u64) {
+                    pos = (pos as u64 / BLOCK_SIZE + 1) as i64 * BLOCK_SIZE as i64;
+                    continue;
+                }
+            }
+            let next = match cached.chunk_at(pos) {
+                Some(chunk) => chunk.clone(),
+                None => {
+                    debug!(fh = fh, start = pos, window = window, "read ahead");
+                    let chunk =
+                        cached.start_chunk(drive.clone(), read_buffer_size, pos, Some(tuner.clone()));
+                    if let Some(old) = cached.chunks.insert(pos, chunk.clone()) {
+                        old.cancel();
+                    }
+                    chunk
+                }
+            };
+            pos = next.end_pos();
+        }
+    }
+
+    fn read_persisted(&self, fh: u64, offset: i64, size: usize) -> Option<Bytes> {
+        let handle = self.handles.get(&fh)?;
+        let cached = self.files.get(&handle.key)?;
+        cached.persisted.read(offset as u64, size)
     }
 
-    pub fn open(&mut self, fh: u64, file_id: String, file_size: u64) {
-        let file = CachedFile {
-            file_id,
-            file_size,
-            start_pos: 0,
-            buffer: Bytes::new(),
+    pub fn read(&mut self, fh: u64, offset: i64, size: u32) -> Result<Bytes, Error> {
+        debug!(fh = fh, offset = offset, size = size, "read file cache");
+        // the file may have grown since it was opened, only the size it had
+        // then can be downloaded
+        let file_size = self
+            .handles
+            .get(&fh)
+            .and_then(|handle| self.files.get(&handle.key))
+            .ok_or(Error::NoEntry)?
+            .file_size as i64;
+        let end_pos = (offset + i64::from(size)).min(file_size);
+        let mut pos = offset;
+        let mut data = BytesMut::with_capacity(size as usize);
+        // a read may span the boundary of two chunks
+        while pos < end_pos {
+            if let Some(part) = self.read_persisted(fh, pos, (end_pos - pos) as usize) {
+                if part.is_empty() {
+                    break;
+                }
+                data.extend_from_slice(&part);
+                pos += part.len() as i64;
+                continue;
+            }
+            let chunk = self.chunk_at(fh, pos)?;
+            self.clock += 1;
+            chunk.touch(self.clock);
+            let len = (end_pos.min(chunk.end_pos()) - pos) as usize;
+            if len == 0 {
+                break;
+            }
+            let part = chunk.read(pos, len)?;
+            data.extend_from_slice(&part);
+            if part.len() < len {
+                // chunk size maybe less than size
+                break;
+            }
+            pos += len as i64;
+        }
+        self.read_ahead(fh, offset, size);
+        Ok(data.freeze())
+    }
+
+    pub fn open(&mut self, fh: u64, file: &AlistFile) {
+        let key = file_key(file);
+        let block_cache = self.block_cache.as_ref();
+        let probe_cache = self.probe_cache.as_ref();
+        let cached = self.files.entry(key.clone()).or_insert_with(|| CachedFile {
+            file_id: file.path.clone(),
+            file_size: file.file.size,
+            persisted: Persisted {
+                blocks: block_cache.map(|cache| cache.open(file)),
+                probe: probe_cache.map(|cache| cache.open(file)),
+            },
+            chunks: BTreeMap::new(),
+            refs: 0,
+        });
+        cached.refs += 1;
+        debug!(fh = fh, path = %file.path, refs = cached.refs, "open file cache");
+        let handle = FileHandle {
+            key,
+            read_ahead: ReadAhead::default(),
         };
-        self.cache.insert(fh, file);
+        self.handles.insert(fh, handle);
     }
 
     pub fn release(&mut self, fh: u64) {
-        self.cache.remove(&fh);
+        let handle = match self.handles.remove(&fh) {
+            Some(handle) => handle,
+            None => return,
+        };
+        let last = match self.files.get_mut(&handle.key) {
+            Some(cached) => {
+                cached.refs -= 1;
+                cached.refs == 0
+            }
+            None => false,
+        };
+        if last {
+            debug!(fh = fh, path = %handle.key.0, "last handle closed, drop file cache");
+            self.files.remove(&handle.key);
+        } else {
+            self.prune(&handle.key);
+        }
     }
 }
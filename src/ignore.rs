@@ -0,0 +1,212 @@
+//! Names that are hidden, refused or never leave the machine
+//!
+//! Rules are glob patterns (`*`, `?` and `[...]`) matched against a single
+//! file name. Hidden names are left out of listings and lookups, refused names
+//! can't be created and local-only names are created in memory but never
+//! uploaded, which keeps desktop metadata files out of the drive.
+use std::str::FromStr;
+
+use clap::ArgEnum;
+
+/// Default rules for the desktop the mount is used from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum IgnorePreset {
+    /// macOS on macOS, Linux elsewhere
+    Auto,
+    Macos,
+    Linux,
+    None,
+}
+
+impl IgnorePreset {
+    fn rules(self) -> (&'static [&'static str], &'static [&'static str]) {
+        // (hidden, local-only)
+        match self {
+            IgnorePreset::Auto if cfg!(target_os = "macos") => IgnorePreset::Macos.rules(),
+            IgnorePreset::Auto => IgnorePreset::Linux.rules(),
+            IgnorePreset::Macos => (
+                &["._*", ".DS_Store"],
+                &[
+                    "._*",
+                    ".DS_Store",
+                    ".Spotlight-V100",
+                    ".Trashes",
+                    ".fseventsd",
+                    ".TemporaryItems",
+                    ".metadata_never_index*",
+                ],
+            ),
+            IgnorePreset::Linux => (
+                &[".Trash-*"],
+                &[".Trash-*", ".directory", ".goutputstream-*"],
+            ),
+            IgnorePreset::None => (&[], &[]),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Glob(Vec<char>);
+
+impl FromStr for Glob {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s.contains('/') {
+            return Err(format!("invalid name pattern {:?}", s));
+        }
+        Ok(Self(s.chars().collect()))
+    }
+}
+
+impl Glob {
+    pub fn matches(&self, name: &str) -> bool {
+        let name: Vec<char> = name.chars().collect();
+        glob_match(&self.0, &name)
+    }
+}
+
+fn glob_match(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|i| glob_match(&pattern[1..], &name[i..])),
+        Some('?') => !name.is_empty() && glob_match(&pattern[1..], &name[1..]),
+        Some('[') => {
+            let end = match pattern.iter().skip(1).position(|c| *c == ']') {
+                Some(pos) => pos + 1,
+                // no closing bracket, match it literally
+                None => return name.first() == Some(&'[') && glob_match(&pattern[1..], &name[1..]),
+            };
+            let c = match name.first() {
+                Some(c) => *c,
+                None => return false,
+            };
+            let (negate, set) = match pattern[1..end].split_first() {
+                Some(('!', rest)) | Some(('^', rest)) => (true, rest),
+                _ => (false, &pattern[1..end]),
+            };
+            let mut found = false;
+            let mut i = 0;
+            while i < set.len() {
+                if i + 2 < set.len() && set[i + 1] == '-' {
+                    found |= set[i] <= c && c <= set[i + 2];
+                    i += 3;
+                } else {
+                    found |= set[i] == c;
+                    i += 1;
+                }
+            }
+            found != negate && glob_match(&pattern[end + 1..], &name[1..])
+        }
+        Some(p) => name.first() == Some(p) && glob_match(&pattern[1..], &name[1..]),
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct IgnorePolicy {
+    hidden: Vec<Glob>,
+    refused: Vec<Glob>,
+    local: Vec<Glob>,
+}
+
+impl IgnorePolicy {
+    /// The rules of `preset` extended with the given patterns
+    pub fn new(preset: IgnorePreset, hidden: Vec<Glob>, refused: Vec<Glob>, local: Vec<Glob>) -> Self {
+        let (preset_hidden, preset_local) = preset.rules();
+        let from_preset = |patterns: &[&str]| -> Vec<Glob> {
+            patterns.iter().filter_map(|p| p.parse().ok()).collect()
+        };
+        let mut policy = Self {
+            hidden: from_preset(preset_hidden),
+            refused: Vec::new(),
+            local: from_preset(preset_local),
+        };
+        policy.hidden.extend(hidden);
+        policy.refused.extend(refused);
+        policy.local.extend(local);
+        policy
+    }
+
+    /// Left out of listings and lookups of the drive
+    pub fn is_hidden(&self, name: &str) -> bool {
+        self.hidden.iter().any(|glob| glob.matches(name))
+    }
+
+    /// Creating it fails with EPERM
+    pub fn is_refused(&self, name: &str) -> bool {
+        self.refused.iter().any(|glob| glob.matches(name))
+    }
+
+    /// Created in memory only, never uploaded
+    pub fn is_local(&self, name: &str) -> bool {
+        self.local.iter().any(|glob| glob.matches(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let name: Vec<char> = name.chars().collect();
+        glob_match(&pattern, &name)
+    }
+
+    #[test]
+    fn star() {
+        assert!(matches("*", ""));
+        assert!(matches("*", ".DS_Store"));
+        assert!(matches("._*", "._photo.jpg"));
+        assert!(matches("._*", "._"));
+        assert!(matches(".Trash-*", ".Trash-1000"));
+        assert!(matches("*.tmp", "a.b.tmp"));
+        assert!(matches("a*b*c", "aXXbYYc"));
+        assert!(!matches("._*", "photo.jpg"));
+        assert!(!matches("*.tmp", "a.tmp.bak"));
+    }
+
+    #[test]
+    fn question_mark() {
+        assert!(matches("?", "a"));
+        assert!(matches("~$?.docx", "~$a.docx"));
+        assert!(!matches("?", ""));
+        assert!(!matches("?", "ab"));
+        assert!(!matches("~$?.docx", "~$.docx"));
+    }
+
+    #[test]
+    fn literal_dots() {
+        assert!(matches(".directory", ".directory"));
+        assert!(!matches(".directory", "xdirectory"));
+        assert!(!matches("*.tmp", "atmp"));
+        assert!(!matches("a.b", "aXb"));
+    }
+
+    #[test]
+    fn anchored() {
+        assert!(!matches(".DS_Store", "x.DS_Store"));
+        assert!(!matches(".DS_Store", ".DS_Store.bak"));
+        assert!(!matches("Thumbs.db", "Thumbs.db~"));
+        assert!(!matches("?.tmp", "ab.tmp"));
+    }
+
+    #[test]
+    fn empty() {
+        assert!(matches("", ""));
+        assert!(!matches("", "a"));
+        assert!(!matches("a", ""));
+        assert!("".parse::<Glob>().is_err());
+        assert!("dir/name".parse::<Glob>().is_err());
+    }
+
+    #[test]
+    fn brackets() {
+        assert!(matches("[abc].txt", "b.txt"));
+        assert!(matches("file[0-9]", "file7"));
+        assert!(!matches("file[0-9]", "filex"));
+        assert!(matches("[!.]*", "name"));
+        assert!(!matches("[!.]*", ".hidden"));
+        assert!(matches("[", "["));
+    }
+}
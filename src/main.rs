@@ -1,17 +1,33 @@
-use std::{env, io, path::PathBuf};
+use std::{env, io, path::PathBuf, time::Duration};
 
 use clap::Parser;
 use fuser::MountOption;
 use tracing_subscriber::{EnvFilter, fmt, prelude::*};
-use drive::{model::Credentials,AlistDrive, DriveConfig};
-use vfs::AlistDriveFileSystem;
+use block_cache::path_hash;
+use cache::{Cache, NegativeCache};
+use dir_index::DirIndex;
+use drive::{model::Credentials,AlistDrive, DriveConfig, RateLimitConfig};
+use file_cache::FileCacheConfig;
+use ignore::{Glob, IgnorePolicy, IgnorePreset};
+use ttl::{Ttl, TtlPolicy, TtlRule};
+use strm::StrmConfig;
+use upload::Uploader;
+use vfs::{AlistDriveFileSystem, Permissions, VfsConfig};
 use tracing::{debug, error, info, warn};
 
 
 
+mod block_cache;
+mod dir_index;
 mod drive;
 mod error;
 mod file_cache;
+mod ignore;
+mod pin;
+mod probe_cache;
+mod strm;
+mod ttl;
+mod upload;
 mod vfs;
 mod cache;
 
@@ -40,13 +56,112 @@ struct Opt {
     /// Allow other users to access the drive
     #[clap(long)]
     allow_other: bool,
+    /// Mount read-only, every operation that would change the drive fails with EROFS
+    #[clap(long)]
+    read_only: bool,
+    /// Owner of every file, defaults to the user running alist-fuse
+    #[clap(long)]
+    uid: Option<u32>,
+    /// Group of every file, defaults to the group running alist-fuse
+    #[clap(long)]
+    gid: Option<u32>,
+    /// Octal bits removed from the directory and file modes
+    #[clap(long, default_value = "022", parse(try_from_str = parse_mode))]
+    umask: u32,
+    /// Octal mode of directories
+    #[clap(long, default_value = "777", parse(try_from_str = parse_mode))]
+    dir_mode: u32,
+    /// Octal mode of files
+    #[clap(long, default_value = "666", parse(try_from_str = parse_mode))]
+    file_mode: u32,
+    /// Let the kernel enforce the reported modes and ownership
+    #[clap(long)]
+    default_permissions: bool,
+    /// Capacity in bytes reported to df, 0 sums the storages that report their capacity to an alist admin
+    #[clap(long, default_value = "0")]
+    capacity: u64,
     /// Read/download buffer size in bytes, defaults to 10MB
     #[clap(short = 'S', long, default_value = "10485760")]
     read_buffer_size: usize,
+    /// Max number of read buffers to prefetch when a file is read sequentially, 0 disables read-ahead
+    #[clap(long, default_value = "4")]
+    read_ahead_chunks: usize,
+    /// Memory in bytes each open file handle may use to keep already read segments of a file, defaults to 32MB
+    #[clap(long, default_value = "33554432")]
+    read_cache_memory: usize,
+    /// Max number of concurrent connections used to download a read buffer of a sequential read
+    #[clap(long, default_value = "4")]
+    download_connections: usize,
+    /// Size limit of the on-disk block cache under the working directory in bytes, 0 disables it
+    #[clap(long, default_value = "0")]
+    disk_cache_size: u64,
+    /// Bytes of the head and of the tail of every file read to keep under the working directory, 0 disables it
+    #[clap(long, default_value = "524288")]
+    probe_cache_size: u64,
+    /// Size limit of the head/tail probe cache in bytes, least recently opened files are dropped first
+    #[clap(long, default_value = "268435456")]
+    probe_cache_limit: u64,
+
+    /// Max number of directory listings to keep in memory
+    #[clap(long, default_value = "1000")]
+    dir_cache_capacity: u64,
+    /// Seconds a directory listing is served from memory before it is fetched again
+    #[clap(long, default_value = "60")]
+    dir_cache_ttl: u64,
+    /// Seconds the kernel may cache a name lookup
+    #[clap(long, default_value = "1")]
+    entry_ttl: u64,
+    /// Seconds the kernel may cache file attributes
+    #[clap(long, default_value = "1")]
+    attr_ttl: u64,
+    /// Override the entry, attribute and directory listing TTLs below a path, can be repeated
+    #[clap(long = "ttl-rule", value_name = "PREFIX=ENTRY,ATTR,DIR")]
+    ttl_rules: Vec<TtlRule>,
+    /// Default rules for desktop metadata files
+    #[clap(long, arg_enum, default_value = "auto")]
+    ignore_preset: IgnorePreset,
+    /// Hide names matching this glob from listings, can be repeated
+    #[clap(long = "hide", value_name = "GLOB")]
+    hide: Vec<Glob>,
+    /// Refuse to create names matching this glob, can be repeated
+    #[clap(long = "refuse", value_name = "GLOB")]
+    refuse: Vec<Glob>,
+    /// Keep created names matching this glob in memory instead of uploading them, can be repeated
+    #[clap(long = "local-only", value_name = "GLOB")]
+    local_only: Vec<Glob>,
+    /// Max number of paths remembered not to exist
+    #[clap(long, default_value = "10000")]
+    negative_cache_capacity: u64,
+    /// Seconds a path is remembered not to exist, also used for negative kernel dentries, 0 disables it
+    #[clap(long, default_value = "10")]
+    negative_cache_ttl: u64,
+
+    /// List every video as a `<name>.strm` file holding its alist download URL,
+    /// for Emby/Jellyfin/Kodi libraries that stream from alist directly
+    #[clap(long)]
+    strm: bool,
+    /// alist address written into `.strm` files, defaults to --api-url
+    #[clap(long)]
+    strm_base_url: Option<String>,
 
-    /// Upload buffer size in bytes, defaults to 16MB
-    #[clap(long, default_value = "16777216")]
-    upload_buffer_size: usize,
+    /// Max list/get API requests per second for interactive lookups, 0 for unlimited
+    #[clap(long, default_value = "10")]
+    api_qps: f64,
+    /// Max in-flight list/get API requests for interactive lookups, 0 for unlimited
+    #[clap(long, default_value = "4")]
+    api_max_concurrency: usize,
+    /// Max list/get API requests per second for background work, 0 for unlimited
+    #[clap(long, default_value = "2")]
+    background_api_qps: f64,
+    /// Max in-flight list/get API requests for background work, 0 for unlimited
+    #[clap(long, default_value = "2")]
+    background_api_max_concurrency: usize,
+}
+
+/// Parse an octal file mode like `755` or `0o755`
+fn parse_mode(s: &str) -> Result<u32, String> {
+    let digits = s.trim_start_matches("0o");
+    u32::from_str_radix(digits, 8).map_err(|err| format!("invalid octal mode {:?}: {}", s, err))
 }
 
 fn main() -> anyhow::Result<()> {
@@ -62,17 +177,25 @@ fn main() -> anyhow::Result<()> {
     }
 
     let opt = Opt::parse();
+    let rate_limit = RateLimitConfig {
+        interactive_qps: opt.api_qps,
+        interactive_concurrency: opt.api_max_concurrency,
+        background_qps: opt.background_api_qps,
+        background_concurrency: opt.background_api_max_concurrency,
+    };
     let drive_config = if opt.api_url.is_empty() {
         DriveConfig {
             api_base_url: opt.api_url.clone(),
             refresh_token_url: format!("{}/api/auth/login/hash",opt.api_url.clone()),
-            workdir: opt.workdir,
+            workdir: opt.workdir.clone(),
+            rate_limit,
         }
     } else {
         DriveConfig {
             api_base_url: opt.api_url.clone(),
             refresh_token_url: format!("{}/api/auth/login/hash",opt.api_url.clone()),
-            workdir: opt.workdir,
+            workdir: opt.workdir.clone(),
+            rate_limit,
         }
     };
 
@@ -89,13 +212,94 @@ fn main() -> anyhow::Result<()> {
     })?;
 
     let _nick_name = drive.nick_name.clone();
-    let vfs = AlistDriveFileSystem::new(drive, opt.read_buffer_size,opt.upload_buffer_size);
+    let file_cache_config = FileCacheConfig {
+        read_buffer_size: opt.read_buffer_size,
+        read_ahead_chunks: opt.read_ahead_chunks,
+        download_connections: opt.download_connections,
+        disk_cache_dir: opt
+            .workdir
+            .as_ref()
+            .filter(|_| opt.disk_cache_size > 0)
+            .map(|dir| dir.join("cache").join("blocks")),
+        disk_cache_size: opt.disk_cache_size,
+        read_cache_memory: opt.read_cache_memory,
+        probe_cache_dir: opt
+            .workdir
+            .as_ref()
+            .filter(|_| opt.probe_cache_size > 0)
+            .map(|dir| dir.join("cache").join("probe")),
+        probe_cache_size: opt.probe_cache_size,
+        probe_cache_limit: opt.probe_cache_limit,
+    };
+    let default_ttl = Ttl {
+        entry: Duration::from_secs(opt.entry_ttl),
+        attr: Duration::from_secs(opt.attr_ttl),
+        dir: Duration::from_secs(opt.dir_cache_ttl),
+    };
+    for rule in &opt.ttl_rules {
+        info!(rule = %rule, "ttl rule");
+    }
+    let ttl = TtlPolicy::new(default_ttl, opt.ttl_rules.clone());
+    let dir_cache = Cache::new(opt.dir_cache_capacity, ttl.clone());
+    let negative_cache = NegativeCache::new(opt.negative_cache_capacity, opt.negative_cache_ttl);
+    let dir_index = opt.workdir.as_ref().and_then(|dir| {
+        let dir = dir.join("cache").join("index");
+        DirIndex::new(dir.clone(), drive.clone(), dir_cache.clone())
+            .map_err(|err| error!(dir = %dir.display(), error = %err, "open dir index failed"))
+            .ok()
+    });
+    let spool_dir = opt
+        .workdir
+        .as_ref()
+        .map(|dir| dir.join("spool"))
+        .unwrap_or_else(|| {
+            // Without a workdir every mount needs a spool of its own, or their
+            // journals would replay each other's uploads
+            let mountpoint = opt.path.canonicalize().unwrap_or_else(|_| opt.path.clone());
+            let key = path_hash(&format!("{}\n{}", opt.api_url, mountpoint.display()));
+            env::temp_dir().join(format!("alist-fuse-spool-{}", key))
+        });
+    let uploader = Uploader::new(
+        drive.clone(),
+        spool_dir,
+        dir_cache.clone(),
+        dir_index.clone(),
+        opt.read_only,
+    )?;
+    let vfs = AlistDriveFileSystem::new(
+        drive,
+        file_cache_config,
+        dir_cache,
+        negative_cache,
+        dir_index,
+        uploader,
+        VfsConfig {
+            ttl,
+            ignore: IgnorePolicy::new(opt.ignore_preset, opt.hide, opt.refuse, opt.local_only),
+            read_only: opt.read_only,
+            permissions: Permissions {
+                uid: opt.uid.unwrap_or_else(|| unsafe { libc::getuid() }),
+                gid: opt.gid.unwrap_or_else(|| unsafe { libc::getgid() }),
+                umask: opt.umask,
+                dir_mode: opt.dir_mode,
+                file_mode: opt.file_mode,
+            },
+            capacity: opt.capacity,
+            strm: opt.strm.then(|| StrmConfig {
+                base_url: opt.strm_base_url.clone().unwrap_or_else(|| opt.api_url.clone()),
+            }),
+        },
+    );
     let mut mount_options = vec![MountOption::AutoUnmount, MountOption::NoAtime];
-    // read only for now
-    // mount_options.push(MountOption::RO);
+    if opt.read_only {
+        mount_options.push(MountOption::RO);
+    }
     if opt.allow_other {
         mount_options.push(MountOption::AllowOther);
     }
+    if opt.default_permissions {
+        mount_options.push(MountOption::DefaultPermissions);
+    }
     if cfg!(target_os = "macos") {
         mount_options.push(MountOption::CUSTOM("local".to_string()));
         mount_options.push(MountOption::CUSTOM("noappledouble".to_string()));
@@ -0,0 +1,289 @@
+//! Pinning files into the block cache
+//!
+//! Pinning a file or directory downloads every file below it into the block
+//! cache in the background and keeps those blocks from being evicted. Pinned
+//! paths are remembered in `<workdir>/cache/pins.json` and warmed again on the
+//! next start, which also picks up files added to a pinned directory since.
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::{Context, Result};
+use parking_lot::Mutex;
+use tracing::{debug, error, info};
+
+use crate::block_cache::{BlockCache, FileBlocks, BLOCK_SIZE};
+use crate::drive::{AlistDrive, AlistFile};
+
+#[derive(Debug, Default, Clone)]
+struct PinProgress {
+    /// Files below the pinned path are still being listed
+    listing: bool,
+    files: usize,
+    done_files: usize,
+    failed_files: usize,
+    bytes: u64,
+    done_bytes: u64,
+}
+
+impl PinProgress {
+    fn status(&self) -> String {
+        if self.listing {
+            return "queued".to_string();
+        }
+        let percent = (self.done_bytes * 100).checked_div(self.bytes).unwrap_or(100);
+        let mut status = if self.done_files + self.failed_files < self.files {
+            format!(
+                "warming {}/{} files, {}% ({}/{} bytes)",
+                self.done_files, self.files, percent, self.done_bytes, self.bytes
+            )
+        } else {
+            format!("pinned {} files, {} bytes", self.done_files, self.done_bytes)
+        };
+        if self.failed_files > 0 {
+            status.push_str(&format!(", {} failed", self.failed_files));
+        }
+        status
+    }
+}
+
+#[derive(Debug)]
+struct PinnerInner {
+    drive: AlistDrive,
+    block_cache: BlockCache,
+    pins_file: PathBuf,
+    // pinned path -> warm up progress
+    pins: Mutex<BTreeMap<String, PinProgress>>,
+    queue: Mutex<Sender<AlistFile>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Pinner {
+    inner: Arc<PinnerInner>,
+}
+
+impl Pinner {
+    /// Start the warm up worker and resume the pins of earlier runs
+    pub fn new(drive: AlistDrive, block_cache: BlockCache, pins_file: PathBuf) -> Self {
+        let (tx, rx) = mpsc::channel();
+        let paths: Vec<String> = fs::read(&pins_file)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        let pins = paths
+            .iter()
+            .map(|path| {
+                let progress = PinProgress {
+                    listing: true,
+                    ..Default::default()
+                };
+                (path.clone(), progress)
+            })
+            .collect();
+        let pinner = Self {
+            inner: Arc::new(PinnerInner {
+                drive: drive.background(),
+                block_cache,
+                pins_file,
+                pins: Mutex::new(pins),
+                queue: Mutex::new(tx),
+            }),
+        };
+        let worker = pinner.clone();
+        thread::spawn(move || worker.run(paths, rx));
+        pinner
+    }
+
+    /// Pin `file`, or every file below it if it is a directory
+    pub fn pin(&self, file: &AlistFile) {
+        {
+            let mut pins = self.inner.pins.lock();
+            if pins.contains_key(&file.path) {
+                return;
+            }
+            let progress = PinProgress {
+                listing: true,
+                ..Default::default()
+            };
+            pins.insert(file.path.clone(), progress);
+            self.save(&pins);
+        }
+        info!(path = %file.path, "pin");
+        let _ = self.inner.queue.lock().send(file.clone());
+    }
+
+    pub fn unpin(&self, path: &str) {
+        let mut pins = self.inner.pins.lock();
+        if pins.remove(path).is_some() {
+            info!(path = %path, "unpin");
+            self.save(&pins);
+            self.inner.block_cache.unpin(path);
+        }
+    }
+
+    pub fn is_pinned(&self, path: &str) -> bool {
+        self.inner.pins.lock().contains_key(path)
+    }
+
+    /// Human readable warm up progress of a pinned path
+    pub fn status(&self, path: &str) -> String {
+        match self.inner.pins.lock().get(path) {
+            Some(progress) => progress.status(),
+            None => "unpinned".to_string(),
+        }
+    }
+
+    fn save(&self, pins: &BTreeMap<String, PinProgress>) {
+        let paths: Vec<&String> = pins.keys().collect();
+        let data = serde_json::to_vec(&paths).unwrap_or_default();
+        if let Err(err) = fs::write(&self.inner.pins_file, data) {
+            error!(path = %self.inner.pins_file.display(), error = %err, "save pins failed");
+        }
+    }
+
+    fn update(&self, root: &str, f: impl FnOnce(&mut PinProgress)) -> bool {
+        match self.inner.pins.lock().get_mut(root) {
+            Some(progress) => {
+                f(progress);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn run(&self, resumed: Vec<String>, rx: Receiver<AlistFile>) {
+        for path in resumed {
+            match self.resolve(&path) {
+                Ok(Some(file)) => self.warm(file),
+                Ok(None) => {
+                    info!(path = %path, "pinned path no longer exists");
+                    self.unpin(&path);
+                }
+                Err(err) => {
+                    error!(path = %path, error = %err, "resolve pinned path failed");
+                    self.update(&path, |progress| {
+                        progress.listing = false;
+                        progress.failed_files += 1;
+                    });
+                }
+            }
+        }
+        while let Ok(file) = rx.recv() {
+            self.warm(file);
+        }
+    }
+
+    /// Look up a pinned path remembered from an earlier run
+    fn resolve(&self, path: &str) -> Result<Option<AlistFile>> {
+        if path == "/" {
+            return Ok(Some(AlistFile::new_root()));
+        }
+        let (parent, name) = path.rsplit_once('/').context("invalid path")?;
+        let parent = if parent.is_empty() { "/" } else { parent };
+        let files = self.inner.drive.list_all(parent)?;
+        Ok(files.into_iter().find(|file| file.file.name == name))
+    }
+
+    fn warm(&self, root: AlistFile) {
+        let root_path = root.path.clone();
+        let mut files = Vec::new();
+        let mut dirs = Vec::new();
+        if root.file.is_dir {
+            dirs.push(root.path);
+        } else {
+            files.push(root);
+        }
+        while let Some(dir) = dirs.pop() {
+            if !self.inner.pins.lock().contains_key(&root_path) {
+                return;
+            }
+            match self.inner.drive.list_all(&dir) {
+                Ok(children) => {
+                    for child in children {
+                        if child.file.is_dir {
+                            dirs.push(child.path);
+                        } else {
+                            files.push(child);
+                        }
+                    }
+                }
+                Err(err) => {
+                    error!(path = %dir, error = %err, "list pinned directory failed");
+                    self.update(&root_path, |progress| progress.failed_files += 1);
+                }
+            }
+        }
+        let bytes = files.iter().map(|file| file.file.size).sum();
+        let count = files.len();
+        if !self.update(&root_path, |progress| {
+            progress.listing = false;
+            progress.files = count;
+            progress.bytes = bytes;
+        }) {
+            return;
+        }
+        for file in files {
+            let blocks = self.inner.block_cache.open(&file);
+            blocks.pin(&file.path);
+            let result = self.download(&root_path, &file, &blocks);
+            if !self.is_pinned(&root_path) {
+                debug!(path = %root_path, "unpinned while warming");
+                self.inner.block_cache.unpin(&root_path);
+                return;
+            }
+            match result {
+                Ok(()) => {
+                    self.update(&root_path, |progress| progress.done_files += 1);
+                }
+                Err(err) => {
+                    error!(path = %file.path, error = %err, "warm pinned file failed");
+                    self.update(&root_path, |progress| progress.failed_files += 1);
+                }
+            }
+        }
+        info!(path = %root_path, status = %self.status(&root_path), "pinned path warmed");
+    }
+
+    /// Download the blocks of `file` that are not cached yet
+    fn download(&self, root: &str, file: &AlistFile, blocks: &FileBlocks) -> Result<()> {
+        let size = blocks.file_size();
+        let mut url = None;
+        let mut offset = 0;
+        while offset < size {
+            let len = BLOCK_SIZE.min(size - offset);
+            if !blocks.contains(offset) {
+                if !self.is_pinned(root) {
+                    return Ok(());
+                }
+                if url.is_none() {
+                    url = Some(self.inner.drive.get_download_url(&file.path)?);
+                }
+                let data = match self.download_block(url.as_deref().unwrap_or_default(), offset, len) {
+                    Ok(data) => data,
+                    Err(err) => {
+                        // the download url may have expired, retry once with a fresh one
+                        debug!(path = %file.path, offset = offset, error = %err, "download block failed, retry");
+                        url = Some(self.inner.drive.get_download_url(&file.path)?);
+                        self.download_block(url.as_deref().unwrap_or_default(), offset, len)?
+                    }
+                };
+                blocks.write(offset, &data);
+            }
+            self.update(root, |progress| progress.done_bytes += len);
+            offset += len;
+        }
+        Ok(())
+    }
+
+    fn download_block(&self, url: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let mut res = self.inner.drive.download(url, offset, len as usize)?;
+        let mut data = Vec::with_capacity(len as usize);
+        res.read_to_end(&mut data)?;
+        anyhow::ensure!(data.len() as u64 == len, "short read");
+        Ok(data)
+    }
+}
@@ -0,0 +1,280 @@
+//! Persistent cache of the head and tail of every file read
+//!
+//! Library scanners open every media file only to read its first and last few
+//! hundred KB, keeping those around lets a rescan of an unchanged library be
+//! served locally. Entries live in `<workdir>/cache/probe/<sha1 of path>.*`,
+//! the least recently opened ones are dropped once the cache outgrows its
+//! size limit.
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info};
+
+use crate::block_cache::{modified_secs, path_hash};
+use crate::drive::AlistFile;
+
+const EXTENSIONS: [&str; 3] = ["meta", "head", "tail"];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ProbeMeta {
+    path: String,
+    size: u64,
+    modified: u64,
+    /// Offset of the first byte of the cached tail
+    tail_start: u64,
+}
+
+#[derive(Debug)]
+struct ProbeEntry {
+    size: u64,
+    last_access: u64,
+}
+
+#[derive(Debug)]
+struct Inner {
+    dir: PathBuf,
+    max_size: u64,
+    used: u64,
+    clock: u64,
+    // hash -> entry
+    entries: HashMap<String, ProbeEntry>,
+    // last access -> hash, oldest first
+    lru: BTreeMap<u64, String>,
+}
+
+impl Inner {
+    fn touch(&mut self, hash: &str) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(entry) = self.entries.get_mut(hash) {
+            self.lru.remove(&entry.last_access);
+            entry.last_access = clock;
+            self.lru.insert(clock, hash.to_string());
+        }
+    }
+
+    fn insert(&mut self, hash: String, size: u64) {
+        self.remove(&hash);
+        self.clock += 1;
+        self.used += size;
+        self.lru.insert(self.clock, hash.clone());
+        self.entries.insert(
+            hash,
+            ProbeEntry {
+                size,
+                last_access: self.clock,
+            },
+        );
+    }
+
+    fn remove(&mut self, hash: &str) {
+        if let Some(entry) = self.entries.remove(hash) {
+            self.lru.remove(&entry.last_access);
+            self.used -= entry.size;
+        }
+    }
+
+    /// Delete the files of `hash`
+    fn purge(&mut self, hash: &str) {
+        self.remove(hash);
+        for ext in EXTENSIONS {
+            let _ = fs::remove_file(self.dir.join(format!("{}.{}", hash, ext)));
+        }
+    }
+
+    /// Delete least recently opened entries until we are under the size limit
+    fn evict(&mut self) {
+        while self.used > self.max_size {
+            let hash = match self.lru.values().next() {
+                Some(hash) => hash.clone(),
+                None => break,
+            };
+            debug!(hash = %hash, "evict probe cache entry");
+            self.purge(&hash);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProbeCache {
+    inner: Arc<Mutex<Inner>>,
+    /// Bytes kept of each end of a file
+    size: u64,
+}
+
+impl ProbeCache {
+    /// Open the cache in `dir`, keeping `size` bytes of each end of a file and
+    /// at most `max_size` bytes in total
+    pub fn new(dir: PathBuf, size: u64, max_size: u64) -> std::io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        // hash -> (last written, bytes)
+        let mut found: HashMap<String, (SystemTime, u64)> = HashMap::new();
+        for entry in fs::read_dir(&dir)?.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let hash = match name.rsplit_once('.') {
+                Some((hash, ext)) if EXTENSIONS.contains(&ext) => hash.to_string(),
+                _ => continue,
+            };
+            if let Ok(meta) = entry.metadata() {
+                let modified = meta.modified().unwrap_or(UNIX_EPOCH);
+                let found = found.entry(hash).or_insert((UNIX_EPOCH, 0));
+                found.0 = found.0.max(modified);
+                found.1 += meta.len();
+            }
+        }
+        let mut found: Vec<_> = found.into_iter().collect();
+        // entries written last are the most recently used ones we know of
+        found.sort_by_key(|(_, (modified, _))| *modified);
+        let mut inner = Inner {
+            dir,
+            max_size,
+            used: 0,
+            clock: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+        };
+        for (hash, (_, size)) in found {
+            inner.insert(hash, size);
+        }
+        inner.evict();
+        info!(
+            dir = %inner.dir.display(),
+            files = inner.entries.len(),
+            size = inner.used,
+            "probe cache loaded"
+        );
+        Ok(Self {
+            inner: Arc::new(Mutex::new(inner)),
+            size,
+        })
+    }
+
+    /// Cached head and tail of `file`, dropping the ones of an older version of it
+    pub fn open(&self, file: &AlistFile) -> FileProbe {
+        let hash = path_hash(&file.path);
+        let dir = self.inner.lock().dir.clone();
+        let probe = FileProbe {
+            cache: self.clone(),
+            dir,
+            meta: ProbeMeta {
+                path: file.path.clone(),
+                size: file.file.size,
+                modified: modified_secs(file),
+                tail_start: file.file.size,
+            },
+            hash,
+            head: Arc::new(RwLock::new(Bytes::new())),
+            tail: Arc::new(RwLock::new(Bytes::new())),
+        };
+        let cached_meta = fs::read(probe.path("meta"))
+            .ok()
+            .and_then(|data| serde_json::from_slice::<ProbeMeta>(&data).ok());
+        match cached_meta {
+            Some(meta)
+                if meta.path == probe.meta.path
+                    && meta.size == probe.meta.size
+                    && meta.modified == probe.meta.modified =>
+            {
+                let head = fs::read(probe.path("head")).unwrap_or_default();
+                let tail = fs::read(probe.path("tail")).unwrap_or_default();
+                if meta.tail_start + tail.len() as u64 == meta.size {
+                    *probe.tail.write() = tail.into();
+                }
+                *probe.head.write() = head.into();
+                self.inner.lock().touch(&probe.hash);
+                debug!(path = %file.path, "probe cache hit");
+            }
+            Some(_) => {
+                debug!(path = %file.path, "file changed, drop probe cache");
+                self.inner.lock().purge(&probe.hash);
+            }
+            None => {}
+        }
+        probe
+    }
+}
+
+/// The cached head and tail of one version of a file
+#[derive(Debug, Clone)]
+pub struct FileProbe {
+    cache: ProbeCache,
+    dir: PathBuf,
+    hash: String,
+    meta: ProbeMeta,
+    head: Arc<RwLock<Bytes>>,
+    tail: Arc<RwLock<Bytes>>,
+}
+
+impl FileProbe {
+    fn path(&self, ext: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", self.hash, ext))
+    }
+
+    /// Read up to `size` bytes at `offset` if they are in the cached head or tail
+    pub fn read(&self, offset: u64, size: usize) -> Option<Bytes> {
+        let head = self.head.read().clone();
+        if offset < head.len() as u64 {
+            let end = (offset as usize + size).min(head.len());
+            return Some(head.slice(offset as usize..end));
+        }
+        let tail = self.tail.read().clone();
+        let tail_start = self.meta.size - tail.len() as u64;
+        if !tail.is_empty() && offset >= tail_start {
+            let start = (offset - tail_start) as usize;
+            let end = (start + size).min(tail.len());
+            return Some(tail.slice(start..end));
+        }
+        None
+    }
+
+    /// Remember the parts of `data`, starting at `offset`, that belong to the
+    /// head or tail of the file
+    pub fn save(&self, offset: u64, data: &[u8]) {
+        let limit = self.cache.size;
+        let end = offset + data.len() as u64;
+        let mut changed = false;
+        if offset == 0 {
+            let len = limit.min(data.len() as u64) as usize;
+            let mut head = self.head.write();
+            if len > head.len() {
+                *head = Bytes::copy_from_slice(&data[..len]);
+                changed |= self.write("head", &head);
+            }
+        }
+        if end == self.meta.size {
+            let start = offset.max(self.meta.size.saturating_sub(limit));
+            let mut tail = self.tail.write();
+            if self.meta.size - start > tail.len() as u64 {
+                *tail = Bytes::copy_from_slice(&data[(start - offset) as usize..]);
+                changed |= self.write("tail", &tail);
+            }
+        }
+        if changed {
+            let mut meta = self.meta.clone();
+            let head_len = self.head.read().len() as u64;
+            let tail_len = self.tail.read().len() as u64;
+            meta.tail_start = self.meta.size - tail_len;
+            self.write("meta", &serde_json::to_vec(&meta).unwrap_or_default());
+            let mut inner = self.cache.inner.lock();
+            inner.insert(self.hash.clone(), head_len + tail_len);
+            inner.evict();
+        }
+    }
+
+    fn write(&self, ext: &str, data: &[u8]) -> bool {
+        let path = self.path(ext);
+        match fs::write(&path, data) {
+            Ok(_) => true,
+            Err(err) => {
+                error!(path = %path.display(), error = %err, "write probe cache failed");
+                false
+            }
+        }
+    }
+}
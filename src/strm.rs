@@ -0,0 +1,95 @@
+//! `.strm` mode for media servers
+//!
+//! Every video is listed as `<name without extension>.strm`, a tiny text file
+//! holding its alist `/d/` URL, so players stream straight from alist or the
+//! storage's CDN. Everything else, like subtitles, NFO files and artwork, is
+//! listed as is. A video whose `.strm` name is taken by another entry of its
+//! directory is listed as `<name>.strm` instead.
+use std::collections::{HashMap, HashSet};
+
+use url::Url;
+
+use crate::drive::AlistFile;
+
+const VIDEO_EXTENSIONS: &[&str] = &[
+    "3gp", "avi", "flv", "iso", "m2ts", "m4v", "mkv", "mov", "mp4", "mpeg", "mpg", "mts", "rm",
+    "rmvb", "ts", "vob", "webm", "wmv",
+];
+
+#[derive(Debug, Clone)]
+pub struct StrmConfig {
+    /// alist address the players can reach
+    pub base_url: String,
+}
+
+fn is_video(name: &str) -> bool {
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => {
+            VIDEO_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str())
+        }
+        _ => false,
+    }
+}
+
+/// Name the entry is listed under if there were no collisions
+fn strm_name(file: &AlistFile) -> String {
+    match file.file.name.rsplit_once('.') {
+        Some((stem, _)) if !file.file.is_dir && is_video(&file.file.name) => format!("{}.strm", stem),
+        _ => file.file.name.clone(),
+    }
+}
+
+/// `.strm` names more than one entry of a directory listing would get
+pub fn collisions(files: &[AlistFile]) -> HashSet<String> {
+    let mut count: HashMap<String, usize> = HashMap::new();
+    for file in files {
+        *count.entry(strm_name(file)).or_default() += 1;
+    }
+    count
+        .into_iter()
+        .filter(|(_, count)| *count > 1)
+        .map(|(name, _)| name)
+        .collect()
+}
+
+impl StrmConfig {
+    /// Download URL of `file`, signed if alist signs its links
+    fn url(&self, file: &AlistFile) -> Option<String> {
+        let mut url = Url::parse(&self.base_url).ok()?;
+        {
+            let mut segments = url.path_segments_mut().ok()?;
+            segments.pop_if_empty().push("d");
+            segments.extend(file.path.split('/').filter(|s| !s.is_empty()));
+        }
+        if !file.file.sign.is_empty() {
+            url.query_pairs_mut().append_pair("sign", &file.file.sign);
+        }
+        Some(url.into())
+    }
+
+    /// The `.strm` file standing in for a video and its content, `None` for
+    /// anything that is listed as is. `collisions` are the ones of its
+    /// directory listing.
+    pub fn to_strm(
+        &self,
+        file: &AlistFile,
+        collisions: &HashSet<String>,
+    ) -> Option<(AlistFile, Vec<u8>)> {
+        if file.file.is_dir || !is_video(&file.file.name) {
+            return None;
+        }
+        let content = format!("{}\n", self.url(file)?).into_bytes();
+        let mut name = strm_name(file);
+        if collisions.contains(&name) {
+            name = format!("{}.strm", file.file.name);
+        }
+        let mut strm = file.clone();
+        strm.path = match file.path.rsplit_once('/') {
+            Some((dir, _)) => format!("{}/{}", dir, name),
+            None => name.clone(),
+        };
+        strm.file.name = name;
+        strm.file.size = content.len() as u64;
+        Some((strm, content))
+    }
+}
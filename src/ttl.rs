@@ -0,0 +1,109 @@
+//! How long the kernel and the directory cache may keep what we told them
+//!
+//! Every lifetime can be overridden below a path prefix with a rule like
+//! `/archive=3600,3600,86400`, the rule with the longest matching prefix wins.
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ttl {
+    /// Lifetime of a name to inode mapping in lookup replies
+    pub entry: Duration,
+    /// Lifetime of file attributes in getattr replies
+    pub attr: Duration,
+    /// Lifetime of a cached directory listing
+    pub dir: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct TtlRule {
+    prefix: String,
+    ttl: Ttl,
+}
+
+impl TtlRule {
+    fn matches(&self, path: &str) -> bool {
+        self.prefix == "/"
+            || path == self.prefix
+            || (path.starts_with(&self.prefix) && path.as_bytes()[self.prefix.len()] == b'/')
+    }
+}
+
+impl FromStr for TtlRule {
+    type Err = String;
+
+    /// Parse `PREFIX=ENTRY,ATTR,DIR` with lifetimes in seconds
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, ttls) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected PREFIX=ENTRY,ATTR,DIR, got {:?}", s))?;
+        if !prefix.starts_with('/') {
+            return Err(format!("path prefix {:?} must start with /", prefix));
+        }
+        let secs = ttls
+            .split(',')
+            .map(|n| n.trim().parse::<u64>().map(Duration::from_secs))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| format!("invalid ttl in {:?}: {}", s, err))?;
+        let ttl = match secs[..] {
+            [entry, attr, dir] => Ttl { entry, attr, dir },
+            _ => return Err(format!("expected 3 ttls in {:?}", s)),
+        };
+        let prefix = match prefix.trim_end_matches('/') {
+            "" => "/",
+            prefix => prefix,
+        };
+        Ok(Self {
+            prefix: prefix.to_string(),
+            ttl,
+        })
+    }
+}
+
+impl fmt::Display for TtlRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}={},{},{}",
+            self.prefix,
+            self.ttl.entry.as_secs(),
+            self.ttl.attr.as_secs(),
+            self.ttl.dir.as_secs()
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TtlPolicy {
+    default: Ttl,
+    // longest prefix first
+    rules: Arc<Vec<TtlRule>>,
+}
+
+impl TtlPolicy {
+    pub fn new(default: Ttl, mut rules: Vec<TtlRule>) -> Self {
+        rules.sort_by_key(|r| std::cmp::Reverse(r.prefix.len()));
+        Self {
+            default,
+            rules: Arc::new(rules),
+        }
+    }
+
+    pub fn get(&self, path: &str) -> Ttl {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(path))
+            .map(|rule| rule.ttl)
+            .unwrap_or(self.default)
+    }
+
+    /// The longest directory listing lifetime of any path
+    pub fn max_dir(&self) -> Duration {
+        self.rules
+            .iter()
+            .map(|rule| rule.ttl.dir)
+            .fold(self.default.dir, Duration::max)
+    }
+}
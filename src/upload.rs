@@ -0,0 +1,788 @@
+//! Write-back staging of written files
+//!
+//! Every handle writing a file stages the data in its own spool file under
+//! `<workdir>/spool`, at whatever offsets it writes. When the handle is
+//! released the spool is handed to a background worker that uploads it with
+//! alist's `PUT /api/fs/put`, replacing the file if it exists. Until that
+//! finished, reads of the file are served from the spool.
+//!
+//! Writing an existing file without truncating it first stages its current
+//! content in the spool. Staging runs in the background while the handle
+//! already writes to the spool, ranges written through the handle are never
+//! overwritten by it. Reads of ranges that haven't been staged yet and the
+//! upload wait for it to finish.
+//!
+//! Queued uploads are journaled in `<workdir>/spool/queue.json` with their
+//! target path, size and failed attempts. Failed uploads are retried with
+//! exponential backoff, and after a restart the journal is picked up again.
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use bytes::Bytes;
+use parking_lot::{Condvar, Mutex, MutexGuard};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
+
+use crate::cache::Cache;
+use crate::dir_index::DirIndex;
+use crate::drive::{AlistDrive, AlistFile};
+
+/// Size of the ranged requests downloading a file into a spool
+const STAGE_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+/// Uploads waiting in the spool directory, kept across restarts
+const JOURNAL_FILE: &str = "queue.json";
+/// A failed upload is retried after this, doubled for every further failure
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(30);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(3600);
+
+#[derive(Debug)]
+struct StagingState {
+    /// Everything below this has been staged
+    staged: u64,
+    /// Content past this was cut off by a truncate and must not be staged
+    limit: u64,
+    /// Ranges at or past `staged` written through the handle, start -> end
+    written: BTreeMap<u64, u64>,
+    done: bool,
+    failed: bool,
+    cancelled: bool,
+}
+
+impl StagingState {
+    fn record(&mut self, mut start: u64, mut end: u64) {
+        if self.done || end <= self.staged {
+            return;
+        }
+        // merge with the ranges it overlaps or touches
+        let merged: Vec<_> = self
+            .written
+            .range(..=end)
+            .rev()
+            .take_while(|(_, e)| **e >= start)
+            .map(|(s, e)| (*s, *e))
+            .collect();
+        for (s, e) in merged {
+            self.written.remove(&s);
+            start = start.min(s);
+            end = end.max(e);
+        }
+        self.written.insert(start, end);
+    }
+}
+
+/// Copying the current content of a file into a spool, in the background
+#[derive(Debug)]
+struct Staging {
+    state: Mutex<StagingState>,
+    cond: Condvar,
+}
+
+impl Staging {
+    fn new(limit: u64) -> Self {
+        Self {
+            state: Mutex::new(StagingState {
+                staged: 0,
+                limit,
+                written: BTreeMap::new(),
+                done: false,
+                failed: false,
+                cancelled: false,
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    fn limit(&self) -> u64 {
+        self.state.lock().limit
+    }
+
+    /// Write staged `data` at `offset` where the handle hasn't written yet,
+    /// false once staging was cancelled
+    fn put(&self, file: &File, offset: u64, data: &[u8]) -> io::Result<bool> {
+        let mut state = self.state.lock();
+        if state.cancelled {
+            return Ok(false);
+        }
+        let end = (offset + data.len() as u64).min(state.limit);
+        let mut pos = offset;
+        for (&start, &stop) in state.written.range(..end).filter(|(_, e)| **e > offset) {
+            if start > pos {
+                file.write_all_at(&data[(pos - offset) as usize..(start - offset) as usize], pos)?;
+            }
+            pos = pos.max(stop);
+        }
+        if pos < end {
+            file.write_all_at(&data[(pos - offset) as usize..(end - offset) as usize], pos)?;
+        }
+        state.staged = state.staged.max(end);
+        let staged = state.staged;
+        state.written.retain(|_, e| *e > staged);
+        self.cond.notify_all();
+        Ok(true)
+    }
+
+    fn finish(&self, failed: bool) {
+        let mut state = self.state.lock();
+        state.done = true;
+        state.failed = failed;
+        state.written.clear();
+        self.cond.notify_all();
+    }
+
+    fn cancel(&self) {
+        self.state.lock().cancelled = true;
+    }
+
+    /// Wait until everything below `end` has been staged
+    fn wait_for(&self, end: u64) -> io::Result<()> {
+        let mut state = self.state.lock();
+        while !state.done && state.staged < end.min(state.limit) {
+            self.cond.wait(&mut state);
+        }
+        if state.failed {
+            return Err(io::Error::new(io::ErrorKind::Other, "staging the file failed"));
+        }
+        Ok(())
+    }
+
+    fn wait(&self) -> io::Result<()> {
+        self.wait_for(u64::MAX)
+    }
+}
+
+/// Data written through one file handle
+#[derive(Debug)]
+pub struct Spool {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    staging: Option<Arc<Staging>>,
+}
+
+impl Spool {
+    fn create(path: PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        Ok(Self {
+            path,
+            file,
+            size: 0,
+            staging: None,
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let end = offset + data.len() as u64;
+        match &self.staging {
+            Some(staging) => {
+                let mut state = staging.state.lock();
+                self.file.write_all_at(data, offset)?;
+                state.record(offset, end);
+            }
+            None => self.file.write_all_at(data, offset)?,
+        }
+        self.size = self.size.max(end);
+        Ok(())
+    }
+
+    pub fn read(&self, offset: u64, size: u32) -> io::Result<Bytes> {
+        if let Some(staging) = &self.staging {
+            staging.wait_for(offset + size as u64)?;
+        }
+        read_at(&self.file, offset, size, self.size)
+    }
+
+    /// Cut off or extend with zeros to `size` bytes
+    pub fn set_len(&mut self, size: u64) -> io::Result<()> {
+        match &self.staging {
+            Some(staging) => {
+                let mut state = staging.state.lock();
+                self.file.set_len(size)?;
+                state.limit = state.limit.min(size);
+            }
+            None => self.file.set_len(size)?,
+        }
+        self.size = size;
+        Ok(())
+    }
+
+    /// Wait until the content of the file has been staged
+    fn wait_staged(&self) -> io::Result<()> {
+        match &self.staging {
+            Some(staging) => staging.wait(),
+            None => Ok(()),
+        }
+    }
+
+    /// Throw away the data, nothing is uploaded
+    pub fn discard(self) {
+        if let Some(staging) = &self.staging {
+            staging.cancel();
+        }
+        if let Err(err) = fs::remove_file(&self.path) {
+            warn!(spool = %self.path.display(), error = %err, "remove spool failed");
+        }
+    }
+}
+
+fn read_at(file: &File, offset: u64, size: u32, len: u64) -> io::Result<Bytes> {
+    let end = (offset + size as u64).min(len);
+    if offset >= end {
+        return Ok(Bytes::new());
+    }
+    let mut buf = vec![0; (end - offset) as usize];
+    file.read_exact_at(&mut buf, offset)?;
+    Ok(buf.into())
+}
+
+/// A spool waiting to be uploaded, as saved in the journal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct QueueEntry {
+    path: String,
+    spool: PathBuf,
+    size: u64,
+    attempts: u32,
+    #[serde(default)]
+    last_error: Option<String>,
+    /// Unix time of the next attempt
+    #[serde(default)]
+    retry_at: u64,
+    #[serde(skip)]
+    uploading: bool,
+    /// Still staging, not journaled or uploaded until done
+    #[serde(skip)]
+    staging: Option<Arc<Staging>>,
+}
+
+impl QueueEntry {
+    fn status(&self) -> String {
+        if self.uploading {
+            return "uploading".to_string();
+        }
+        if self.staging.is_some() {
+            return "staging".to_string();
+        }
+        match &self.last_error {
+            Some(err) => format!(
+                "retry in {}s after {} failed attempts: {}",
+                self.retry_at.saturating_sub(unix_now()),
+                self.attempts,
+                err
+            ),
+            None => "queued".to_string(),
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Remove the entries for `path` that are not being uploaded and their
+/// spools, whether there were any
+fn drop_waiting(queue: &mut Vec<QueueEntry>, path: &str) -> bool {
+    let len = queue.len();
+    queue.retain(|entry| {
+        let waiting = entry.path == path && !entry.uploading;
+        if waiting {
+            if let Some(staging) = &entry.staging {
+                staging.cancel();
+            }
+            let _ = fs::remove_file(&entry.spool);
+        }
+        !waiting
+    });
+    queue.len() != len
+}
+
+fn backoff(attempts: u32) -> u64 {
+    let secs = RETRY_BASE_DELAY.as_secs().saturating_mul(1 << attempts.min(16));
+    secs.min(RETRY_MAX_DELAY.as_secs())
+}
+
+struct Inner {
+    drive: AlistDrive,
+    dir: PathBuf,
+    journal: PathBuf,
+    next_id: AtomicU64,
+    dir_cache: Cache,
+    dir_index: Option<DirIndex>,
+    // oldest first, a path may have several spools queued
+    queue: Mutex<Vec<QueueEntry>>,
+    // signalled whenever an upload attempt finished
+    idle: Condvar,
+    // wakes up the worker
+    wake: Mutex<Sender<()>>,
+}
+
+#[derive(Clone)]
+pub struct Uploader {
+    inner: Arc<Inner>,
+}
+
+impl Uploader {
+    /// Keep spools in `dir` and upload them with `drive`, the listings of
+    /// directories that got a new file are dropped from `dir_cache` and
+    /// `dir_index`. Uploads queued by an earlier run are resumed, unless the
+    /// mount is `read_only`: then nothing is touched and no worker runs.
+    pub fn new(
+        drive: AlistDrive,
+        dir: PathBuf,
+        dir_cache: Cache,
+        dir_index: Option<DirIndex>,
+        read_only: bool,
+    ) -> io::Result<Self> {
+        let journal = dir.join(JOURNAL_FILE);
+        let (tx, rx) = mpsc::channel();
+        let uploader = Self {
+            inner: Arc::new(Inner {
+                drive,
+                dir,
+                journal,
+                next_id: AtomicU64::new(0),
+                dir_cache,
+                dir_index,
+                queue: Mutex::new(Vec::new()),
+                idle: Condvar::new(),
+                wake: Mutex::new(tx),
+            }),
+        };
+        if read_only {
+            return Ok(uploader);
+        }
+        let dir = &uploader.inner.dir;
+        fs::create_dir_all(dir)?;
+        let mut queue: Vec<QueueEntry> = fs::read(&uploader.inner.journal)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        queue.retain(|entry| {
+            let exists = entry.spool.exists();
+            if !exists {
+                error!(path = %entry.path, spool = %entry.spool.display(), "queued spool is gone, upload dropped");
+            }
+            exists
+        });
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let is_spool = path.extension().map(|ext| ext == "spool").unwrap_or(false);
+            if is_spool && !queue.iter().any(|entry| entry.spool == path) {
+                // the handle writing it was never released
+                warn!(spool = %path.display(), "spool of an earlier run was not finished, not uploaded");
+            }
+        }
+        if !queue.is_empty() {
+            info!(count = queue.len(), "resume queued uploads");
+        }
+        *uploader.inner.queue.lock() = queue;
+        uploader.save(&uploader.inner.queue.lock());
+        let worker = uploader.clone();
+        thread::spawn(move || worker.run(rx));
+        Ok(uploader)
+    }
+
+    fn save(&self, queue: &[QueueEntry]) {
+        let tmp = self.inner.journal.with_extension("tmp");
+        // a spool that is still staging would be resumed half empty
+        let queue: Vec<_> = queue.iter().filter(|entry| entry.staging.is_none()).collect();
+        let res = serde_json::to_vec_pretty(&queue)
+            .map_err(io::Error::from)
+            .and_then(|data| fs::write(&tmp, data))
+            .and_then(|_| fs::rename(&tmp, &self.inner.journal));
+        if let Err(err) = res {
+            error!(path = %self.inner.journal.display(), error = %err, "save upload queue failed");
+        }
+    }
+
+    /// The newest spool queued for `path`
+    fn pending_spool(&self, path: &str) -> Option<(PathBuf, Option<Arc<Staging>>)> {
+        self.inner
+            .queue
+            .lock()
+            .iter()
+            .rev()
+            .find(|entry| entry.path == path)
+            .map(|entry| (entry.spool.clone(), entry.staging.clone()))
+    }
+
+    /// A new empty spool
+    pub fn create_spool(&self) -> io::Result<Spool> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        Spool::create(self.inner.dir.join(format!("{}-{}.spool", now, id)))
+    }
+
+    /// A spool holding the first `len` bytes of `file`, taken from the spool
+    /// waiting to be uploaded to it or else downloaded, and padded with zeros
+    /// if the file is shorter. The content is staged in the background.
+    pub fn stage(&self, file: &AlistFile, len: u64) -> io::Result<Spool> {
+        let src = self.pending_spool(&file.path);
+        self.start_staging(file, src, len)
+    }
+
+    /// Like `stage`, with the content taken from `src`, another handle's
+    /// spool of `file`
+    pub fn stage_from(&self, file: &AlistFile, src: &Spool, len: u64) -> io::Result<Spool> {
+        self.start_staging(file, Some((src.path.clone(), src.staging.clone())), len)
+    }
+
+    fn start_staging(
+        &self,
+        file: &AlistFile,
+        src: Option<(PathBuf, Option<Arc<Staging>>)>,
+        len: u64,
+    ) -> io::Result<Spool> {
+        let mut spool = self.create_spool()?;
+        let res = spool.set_len(len).and_then(|_| spool.file.try_clone());
+        let dst = match res {
+            Ok(dst) => dst,
+            Err(err) => {
+                spool.discard();
+                return Err(err);
+            }
+        };
+        if len == 0 {
+            return Ok(spool);
+        }
+        let staging = Arc::new(Staging::new(len));
+        spool.staging = Some(staging.clone());
+        let uploader = self.clone();
+        let path = file.path.clone();
+        let size = file.file.size;
+        thread::spawn(move || {
+            let res = uploader.fill(&path, size, src, &dst, &staging);
+            if let Err(err) = &res {
+                error!(path = %path, error = %err, "stage file failed");
+            }
+            staging.finish(res.is_err());
+        });
+        Ok(spool)
+    }
+
+    /// Stage the content of the file at `path`, `size` bytes on the server,
+    /// into `dst`
+    fn fill(
+        &self,
+        path: &str,
+        size: u64,
+        src: Option<(PathBuf, Option<Arc<Staging>>)>,
+        dst: &File,
+        staging: &Staging,
+    ) -> Result<()> {
+        if let Some((src, src_staging)) = src {
+            if let Some(src_staging) = src_staging {
+                src_staging.wait()?;
+            }
+            // the upload may finish and the spool go away in the meantime
+            if let Ok(src) = File::open(&src) {
+                let len = src.metadata()?.len();
+                return self.copy(&src, len, dst, staging);
+            }
+        }
+        self.download(path, size, dst, staging)
+    }
+
+    fn copy(&self, src: &File, len: u64, dst: &File, staging: &Staging) -> Result<()> {
+        let mut offset = 0;
+        while offset < staging.limit().min(len) {
+            let data = read_at(src, offset, STAGE_CHUNK_SIZE as u32, len)?;
+            if !staging.put(dst, offset, &data)? {
+                break;
+            }
+            offset += data.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn download(&self, path: &str, size: u64, dst: &File, staging: &Staging) -> Result<()> {
+        if staging.limit().min(size) == 0 {
+            return Ok(());
+        }
+        debug!(path = %path, size = size, "stage remote file");
+        let url = self.inner.drive.get_download_url(path)?;
+        anyhow::ensure!(!url.is_empty(), "no download url for {}", path);
+        let mut offset = 0;
+        loop {
+            let len = staging.limit().min(size);
+            if offset >= len {
+                break;
+            }
+            let chunk = STAGE_CHUNK_SIZE.min(len - offset);
+            let mut data = Vec::with_capacity(chunk as usize);
+            self.inner
+                .drive
+                .download(&url, offset, chunk as usize)?
+                .read_to_end(&mut data)?;
+            if data.is_empty() {
+                break;
+            }
+            data.truncate(chunk as usize);
+            if !staging.put(dst, offset, &data)? {
+                break;
+            }
+            offset += data.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Upload `spool` to `path` in the background
+    pub fn enqueue(&self, path: &str, spool: Spool) {
+        // the journal must never point at data that is not on disk yet
+        if spool.staging.is_none() {
+            if let Err(err) = spool.file.sync_all() {
+                warn!(spool = %spool.path.display(), error = %err, "sync spool failed");
+            }
+        }
+        let Spool {
+            path: spool,
+            size,
+            staging,
+            ..
+        } = spool;
+        debug!(path = %path, size = size, "queue upload");
+        {
+            let mut queue = self.inner.queue.lock();
+            // older content still waiting for the same path is replaced
+            drop_waiting(&mut queue, path);
+            queue.push(QueueEntry {
+                path: path.to_string(),
+                spool: spool.clone(),
+                size,
+                attempts: 0,
+                last_error: None,
+                retry_at: 0,
+                uploading: false,
+                staging: staging.clone(),
+            });
+            self.save(&queue);
+        }
+        match staging {
+            Some(staging) => {
+                let uploader = self.clone();
+                thread::spawn(move || uploader.staged(spool, &staging));
+            }
+            None => {
+                let _ = self.inner.wake.lock().send(());
+            }
+        }
+    }
+
+    /// Queue the upload of `spool` for real once it has been staged
+    fn staged(&self, spool: PathBuf, staging: &Staging) {
+        let res = staging.wait();
+        if res.is_ok() {
+            if let Err(err) = File::open(&spool).and_then(|file| file.sync_all()) {
+                warn!(spool = %spool.display(), error = %err, "sync spool failed");
+            }
+        }
+        let mut queue = self.inner.queue.lock();
+        // cancelled or replaced in the meantime
+        let pos = match queue.iter().position(|entry| entry.spool == spool) {
+            Some(pos) => pos,
+            None => return,
+        };
+        match res {
+            Ok(()) => {
+                queue[pos].staging = None;
+                self.save(&queue);
+                drop(queue);
+                let _ = self.inner.wake.lock().send(());
+            }
+            Err(err) => {
+                let entry = queue.remove(pos);
+                error!(path = %entry.path, error = %err, "upload dropped");
+                let _ = fs::remove_file(&entry.spool);
+                self.save(&queue);
+            }
+        }
+    }
+
+    /// Upload `spool` to `path` right away
+    pub fn upload(&self, path: &str, spool: &Spool) -> Result<()> {
+        spool.wait_staged()?;
+        {
+            // an older upload still in progress would land after this one,
+            // and older content queued for the same path must not follow it
+            let mut queue = self.wait_idle(path);
+            if drop_waiting(&mut queue, path) {
+                self.save(&queue);
+            }
+        }
+        self.inner.drive.put_file(path, &spool.path)?;
+        self.invalidate_parent(path);
+        Ok(())
+    }
+
+    /// The queue once no upload to `path` or below it is in progress
+    fn wait_idle(&self, path: &str) -> MutexGuard<'_, Vec<QueueEntry>> {
+        let prefix = format!("{}/", path.trim_end_matches('/'));
+        let mut queue = self.inner.queue.lock();
+        while queue
+            .iter()
+            .any(|entry| entry.uploading && (entry.path == path || entry.path.starts_with(&prefix)))
+        {
+            self.inner.idle.wait(&mut queue);
+        }
+        queue
+    }
+
+    /// Forget the uploads queued for a removed file, waiting for one in
+    /// progress to finish first. Whether any were dropped.
+    pub fn cancel(&self, path: &str) -> bool {
+        let mut queue = self.wait_idle(path);
+        let dropped = drop_waiting(&mut queue, path);
+        if dropped {
+            debug!(path = %path, "queued upload cancelled");
+            self.save(&queue);
+        }
+        dropped
+    }
+
+    /// Send the uploads queued for `from` or below it to `to` instead,
+    /// replacing the ones queued for `to`. Whether any were moved.
+    pub fn rename(&self, from: &str, to: &str) -> bool {
+        let prefix = format!("{}/", from.trim_end_matches('/'));
+        let mut queue = self.wait_idle(from);
+        let mut changed = drop_waiting(&mut queue, to);
+        let mut moved = false;
+        for entry in queue.iter_mut() {
+            if entry.path == from || entry.path.starts_with(&prefix) {
+                entry.path = format!("{}{}", to, &entry.path[from.len()..]);
+                moved = true;
+            }
+        }
+        changed |= moved;
+        if changed {
+            debug!(from = %from, to = %to, "queued upload renamed");
+            self.save(&queue);
+        }
+        moved
+    }
+
+    /// Whether a spool is waiting to be uploaded to `path`
+    pub fn is_pending(&self, path: &str) -> bool {
+        self.inner.queue.lock().iter().any(|entry| entry.path == path)
+    }
+
+    /// Human readable upload state of `path`
+    pub fn status(&self, path: &str) -> String {
+        self.inner
+            .queue
+            .lock()
+            .iter()
+            .rev()
+            .find(|entry| entry.path == path)
+            .map(QueueEntry::status)
+            .unwrap_or_else(|| "uploaded".to_string())
+    }
+
+    /// Read the spool waiting to be uploaded to `path`, `None` if there is none
+    pub fn read_pending(&self, path: &str, offset: u64, size: u32) -> Option<io::Result<Bytes>> {
+        let (spool, staging) = self.pending_spool(path)?;
+        if let Some(Err(err)) = staging.map(|staging| staging.wait_for(offset + size as u64)) {
+            return Some(Err(err));
+        }
+        // the upload may finish and the spool go away in the meantime
+        let file = File::open(&spool).ok()?;
+        Some(file.metadata().and_then(|meta| read_at(&file, offset, size, meta.len())))
+    }
+
+    fn invalidate_parent(&self, path: &str) {
+        let parent = match path.rsplit_once('/') {
+            Some(("", _)) | None => "/",
+            Some((parent, _)) => parent,
+        };
+        self.inner.dir_cache.invalidate(Path::new(parent));
+        if let Some(index) = &self.inner.dir_index {
+            index.remove(parent);
+        }
+    }
+
+    /// The oldest entry due for an attempt, or how long to wait for one
+    fn next_due(&self) -> Result<QueueEntry, Option<Duration>> {
+        let now = unix_now();
+        let mut queue = self.inner.queue.lock();
+        if let Some(entry) = queue
+            .iter_mut()
+            .find(|entry| !entry.uploading && entry.staging.is_none() && entry.retry_at <= now)
+        {
+            entry.uploading = true;
+            return Ok(entry.clone());
+        }
+        Err(queue
+            .iter()
+            .filter(|entry| entry.staging.is_none())
+            .map(|entry| Duration::from_secs(entry.retry_at.saturating_sub(now)))
+            .min())
+    }
+
+    fn run(&self, rx: Receiver<()>) {
+        loop {
+            let entry = match self.next_due() {
+                Ok(entry) => entry,
+                Err(wait) => {
+                    let res = match wait {
+                        Some(wait) => rx.recv_timeout(wait).map_err(|err| err == RecvTimeoutError::Disconnected),
+                        None => rx.recv().map_err(|_| true),
+                    };
+                    if res == Err(true) {
+                        return;
+                    }
+                    continue;
+                }
+            };
+            let res = self.inner.drive.put_file(&entry.path, &entry.spool);
+            if res.is_ok() {
+                self.invalidate_parent(&entry.path);
+            }
+            let mut queue = self.inner.queue.lock();
+            let pos = queue.iter().position(|e| e.spool == entry.spool);
+            match res {
+                Ok(()) => {
+                    info!(path = %entry.path, size = entry.size, "uploaded");
+                    if let Some(pos) = pos {
+                        queue.remove(pos);
+                    }
+                    if let Err(err) = fs::remove_file(&entry.spool) {
+                        warn!(spool = %entry.spool.display(), error = %err, "remove spool failed");
+                    }
+                }
+                Err(err) => {
+                    if let Some(e) = pos.and_then(|pos| queue.get_mut(pos)) {
+                        let delay = backoff(e.attempts);
+                        e.uploading = false;
+                        e.attempts += 1;
+                        e.retry_at = unix_now() + delay;
+                        e.last_error = Some(err.to_string());
+                        error!(path = %e.path, attempts = e.attempts, retry_in = delay, error = %err, "upload failed");
+                    }
+                }
+            }
+            self.save(&queue);
+            self.inner.idle.notify_all();
+        }
+    }
+}
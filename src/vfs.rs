@@ -3,38 +3,57 @@
 //! https://github.com/gz/btfs is used as a reference.
 use std::ffi::{OsStr, OsString};
 use std::path::Path;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use std::{collections::BTreeMap};
+use std::mem::size_of;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::collections::{BTreeMap, HashMap, HashSet};
 
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+use bytes::Bytes;
 use fuser::{
     FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory,ReplyCreate, ReplyEmpty, ReplyEntry,
-    ReplyOpen,ReplyWrite, Request, FUSE_ROOT_ID,
+    ReplyOpen,ReplyWrite, ReplyStatfs, ReplyXattr, Request, TimeOrNow, FUSE_ROOT_ID,
 };
-use tracing::{debug,info,error};
+use tracing::{debug, error, info, warn};
 
 use sha1::{Sha1, Digest};
 use serde::de::DeserializeOwned;
 use quick_xml::de::from_str;
-use quick_xml::Writer;
-use quick_xml::se::Serializer as XmlSerializer;
 use serde_json::json;
 use serde::{Serialize,Deserialize};
 
 
 
-use crate::cache::Cache;
+use crate::cache::{Cache, NegativeCache};
+use crate::dir_index::DirIndex;
 use crate::drive::{AlistDrive, AlistFile};
 use crate::drive::model::*;
 
 use crate::error::Error;
-use crate::file_cache::FileCache;
-
-const TTL: Duration = Duration::from_secs(1);
+use crate::file_cache::{FileCache, FileCacheConfig};
+use crate::ignore::IgnorePolicy;
+use crate::strm::{self, StrmConfig};
+use crate::upload::{Spool, Uploader};
+use crate::ttl::{Ttl, TtlPolicy};
+
+/// Set to 1 to pin a file or directory into the disk cache, 0 to unpin it
+const PIN_XATTR: &str = "user.alist.pin";
+/// Read only warm up progress of a pinned path
+const PIN_STATUS_XATTR: &str = "user.alist.pin_status";
+/// Read only state of the upload of written data
+const UPLOAD_STATUS_XATTR: &str = "user.alist.upload_status";
 const BLOCK_SIZE: u64 = 4194304;
 
 
 
+/// How long the capacity reported by statfs is reused
+const STATFS_CACHE_TTL: Duration = Duration::from_secs(300);
+/// Capacity reported when neither alist nor the config know it
+const UNKNOWN_CAPACITY: u64 = 1 << 50;
+const STATFS_BLOCK_SIZE: u64 = 4096;
+/// Inode count reported by statfs, the drive has no real limit
+const STATFS_MAX_FILES: u64 = u32::MAX as u64;
+/// How often the size of the inode table is logged
+const MEMORY_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
 const FILE_HANDLE_READ_BIT: u64 = 1 << 63;
 const FILE_HANDLE_WRITE_BIT: u64 = 1 << 62;
 
@@ -43,6 +62,16 @@ const FILE_HANDLE_WRITE_BIT: u64 = 1 << 62;
 pub struct Inode {
     children: BTreeMap<OsString, u64>,
     parent: u64,
+    /// Bumped whenever the inode number is reused for a different file
+    generation: u64,
+    /// `children` holds the full listing of the directory
+    listed: bool,
+    /// References the kernel holds, dropped by `forget`
+    lookups: u64,
+    /// Open file handles
+    handles: u64,
+    /// Created by us and never uploaded, see `IgnorePolicy::is_local`
+    local: bool,
 }
 
 impl Inode {
@@ -50,6 +79,11 @@ impl Inode {
         Self {
             children: BTreeMap::new(),
             parent,
+            generation: 0,
+            listed: false,
+            lookups: 0,
+            handles: 0,
+            local: false,
         }
     }
 
@@ -59,58 +93,101 @@ impl Inode {
 }
 
 
-#[derive(Debug, Clone)]
+/// A file being written through one handle
+#[derive(Debug)]
 struct UploadState {
-    size: u64,
-    buffer: BytesMut,
-    chunk_count: u64,
-    chunk: u64,
-    upload_id: String,
-    oss_args: Option<OssArgs>,
-    sha1: Option<String>,
-    upload_tags:CompleteMultipartUpload,
+    spool: Spool,
+    /// Written to since the last upload
+    dirty: bool,
+    /// The file was unlinked while open, nothing is uploaded
+    removed: bool,
 }
 
-impl Default for UploadState {
-    fn default() -> Self {
-        let mut upload_tags = CompleteMultipartUpload{Part:vec![]};
-        Self {
-            size: 0,
-            buffer: BytesMut::new(),
-            chunk_count: 0,
-            chunk: 1,
-            upload_id: String::new(),
-            oss_args: None,
-            sha1: None,
-            upload_tags: upload_tags,
-        }
+
+/// Ownership and modes reported for every file
+#[derive(Debug, Clone, Copy)]
+pub struct Permissions {
+    pub uid: u32,
+    pub gid: u32,
+    pub umask: u32,
+    pub dir_mode: u32,
+    pub file_mode: u32,
+}
+
+impl Permissions {
+    fn mode(&self, is_dir: bool) -> u16 {
+        let mode = if is_dir { self.dir_mode } else { self.file_mode };
+        (mode & !self.umask & 0o7777) as u16
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct VfsConfig {
+    pub ttl: TtlPolicy,
+    pub ignore: IgnorePolicy,
+    /// Fail every mutating operation with EROFS
+    pub read_only: bool,
+    pub permissions: Permissions,
+    /// Capacity in bytes reported by statfs, 0 to ask alist
+    pub capacity: u64,
+    /// List videos as `.strm` files holding their download URL
+    pub strm: Option<StrmConfig>,
+}
 
 pub struct AlistDriveFileSystem {
     drive: AlistDrive,
     file_cache: FileCache,
+    dir_cache: Cache,
+    negative_cache: NegativeCache,
+    dir_index: Option<DirIndex>,
+    config: VfsConfig,
     files: BTreeMap<u64, AlistFile>,
     inodes: BTreeMap<u64, Inode>,
+    // path -> inode, so a file keeps its inode across listings
+    paths: HashMap<String, u64>,
+    // content of local-only files
+    local_data: HashMap<u64, Vec<u8>>,
+    // content of `.strm` files standing in for videos
+    strm_data: HashMap<u64, Vec<u8>>,
+    last_memory_report: Instant,
+    // (fetched at, (used, total))
+    quota: Option<(Instant, (u64, u64))>,
     next_inode: u64,
     next_fh: u64,
-    upload_buffer_size:usize,
-    upload_state: UploadState,
+    // (inode, file handle) -> upload in progress through that handle
+    uploads: HashMap<(u64, u64), UploadState>,
+    uploader: Uploader,
 }
 
 impl AlistDriveFileSystem {
-    pub fn new(drive: AlistDrive, read_buffer_size: usize,upload_buffer_size:usize) -> Self {
-        let file_cache = FileCache::new(drive.clone(), read_buffer_size);
+    pub fn new(
+        drive: AlistDrive,
+        file_cache_config: FileCacheConfig,
+        dir_cache: Cache,
+        negative_cache: NegativeCache,
+        dir_index: Option<DirIndex>,
+        uploader: Uploader,
+        config: VfsConfig,
+    ) -> Self {
+        let file_cache = FileCache::new(drive.clone(), file_cache_config);
         Self {
             drive,
             file_cache,
+            dir_cache,
+            negative_cache,
+            dir_index,
+            config,
             files: BTreeMap::new(),
             inodes: BTreeMap::new(),
+            paths: HashMap::new(),
+            local_data: HashMap::new(),
+            strm_data: HashMap::new(),
+            last_memory_report: Instant::now(),
+            quota: None,
             next_inode: 1,
             next_fh: 1,
-            upload_buffer_size:upload_buffer_size,
-            upload_state: UploadState::default(),
+            uploads: HashMap::new(),
+            uploader,
         }
     }
 
@@ -120,6 +197,295 @@ impl AlistDriveFileSystem {
         self.next_inode
     }
 
+    /// Inode of `path`, allocating a new one for a path we haven't seen
+    fn inode_for_path(&mut self, path: &str) -> u64 {
+        if let Some(ino) = self.paths.get(path) {
+            return *ino;
+        }
+        let ino = self.next_inode();
+        self.paths.insert(path.to_string(), ino);
+        ino
+    }
+
+    /// Generation of `ino` to hand to the kernel along with it
+    fn generation(&self, ino: u64) -> u64 {
+        self.inodes.get(&ino).map(|inode| inode.generation).unwrap_or(0)
+    }
+
+    /// Put the inode of a file we created in place, the kernel must not take
+    /// it for whatever had the same inode number before
+    fn insert_inode(&mut self, ino: u64, mut inode: Inode) {
+        if let Some(old) = self.inodes.get(&ino) {
+            inode.generation = old.generation + 1;
+        }
+        self.inodes.insert(ino, inode);
+    }
+
+    /// Move the inodes of `from` and everything below it to `to`
+    fn move_path(&mut self, from: &str, to: &str) {
+        let prefix = format!("{}/", from.trim_end_matches('/'));
+        let moved: Vec<String> = self
+            .paths
+            .keys()
+            .filter(|path| path.as_str() == from || path.starts_with(&prefix))
+            .cloned()
+            .collect();
+        for old_path in moved {
+            if let Some(ino) = self.paths.remove(&old_path) {
+                let new_path = format!("{}{}", to, &old_path[from.len()..]);
+                if let Some(file) = self.files.get_mut(&ino) {
+                    file.path = new_path.clone();
+                }
+                self.paths.insert(new_path, ino);
+            }
+        }
+    }
+
+    /// Create a file or directory that only exists in memory
+    fn create_local(&mut self, parent: u64, name: &OsStr, is_dir: bool) -> Result<FileAttr, Error> {
+        let parent_path = self.files.get(&parent).ok_or(Error::ParentNotFound)?.path.clone();
+        let path = child_path(&parent_path, name);
+        debug!(path = %path, "create local-only entry");
+        let now = DateTime::new(SystemTime::now());
+        let file = AlistFile {
+            path: path.clone(),
+            file: ResFile {
+                name: name.to_string_lossy().to_string(),
+                size: 0,
+                is_dir,
+                created: now.clone(),
+                modified: now,
+                sign: String::new(),
+                thumb: String::new(),
+                hashinfo: String::new(),
+            },
+        };
+        let ino = self.inode_for_path(&path);
+        let attr = file.to_file_attr(ino, &self.config.permissions);
+        let mut inode = Inode::new(parent);
+        inode.local = true;
+        inode.listed = is_dir;
+        self.files.insert(ino, file);
+        self.insert_inode(ino, inode);
+        if !is_dir {
+            self.local_data.insert(ino, Vec::new());
+        }
+        if let Some(parent_inode) = self.inodes.get_mut(&parent) {
+            parent_inode.add_child(name.to_os_string(), ino);
+        }
+        Ok(attr)
+    }
+
+    fn is_local(&self, ino: u64) -> bool {
+        self.inodes.get(&ino).map(|inode| inode.local).unwrap_or(false)
+    }
+
+    fn remove_local(&mut self, parent: u64, name: &OsStr, ino: u64) {
+        debug!(inode = ino, "remove local-only entry");
+        if let Some(parent_inode) = self.inodes.get_mut(&parent) {
+            parent_inode.children.remove(name);
+        }
+        if let Some(file) = self.files.remove(&ino) {
+            self.paths.remove(&file.path);
+        }
+        self.inodes.remove(&ino);
+        self.local_data.remove(&ino);
+    }
+
+    /// Queue the data of a local-only file for upload to `path`, from then on
+    /// it is a regular file
+    fn upload_local(&mut self, ino: u64, path: &str) -> Result<(), Error> {
+        let mut spool = self.uploader.create_spool().map_err(|err| {
+            error!(path = %path, error = %err, "create spool failed");
+            Error::SpoolFailed
+        })?;
+        let data = self.local_data.get(&ino).map(Vec::as_slice).unwrap_or_default();
+        if let Err(err) = spool.write(0, data) {
+            error!(path = %path, error = %err, "write spool failed");
+            spool.discard();
+            return Err(Error::SpoolFailed);
+        }
+        self.local_data.remove(&ino);
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.local = false;
+        }
+        self.uploader.enqueue(path, spool);
+        Ok(())
+    }
+
+    /// Drop the name of an unlinked file, open handles keep its inode
+    fn unlinked(&mut self, parent: u64, name: &OsStr, path: &str) {
+        if let Some(parent_inode) = self.inodes.get_mut(&parent) {
+            parent_inode.children.remove(name);
+        }
+        self.paths.remove(path);
+    }
+
+    /// Keep the inode of a file renamed by us, and of everything below it
+    fn renamed(&mut self, ino: u64, parent: u64, name: &OsStr, new_parent: u64, new_name: &OsStr) {
+        let (old_path, new_parent_path) = match (self.files.get(&ino), self.files.get(&new_parent)) {
+            (Some(file), Some(new_parent)) => (file.path.clone(), new_parent.path.clone()),
+            _ => return,
+        };
+        let new_path = child_path(&new_parent_path, new_name);
+        // a file replaced by the rename is gone
+        if let Some(replaced) = self.paths.remove(&new_path) {
+            self.files.remove(&replaced);
+            self.inodes.remove(&replaced);
+        }
+        self.move_path(&old_path, &new_path);
+        if let Some(file) = self.files.get_mut(&ino) {
+            file.file.name = new_name.to_string_lossy().to_string();
+        }
+        if let Some(inode) = self.inodes.get_mut(&parent) {
+            inode.children.remove(name);
+        }
+        if let Some(inode) = self.inodes.get_mut(&new_parent) {
+            inode.add_child(new_name.to_os_string(), ino);
+        }
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.parent = new_parent;
+        }
+    }
+
+    /// The kernel got a reference to `ino` from a lookup reply
+    fn remember(&mut self, ino: u64) {
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.lookups += 1;
+        }
+    }
+
+    /// Drop `ino` once neither the kernel nor an open handle references it
+    fn maybe_evict(&mut self, ino: u64) {
+        if ino == FUSE_ROOT_ID {
+            return;
+        }
+        let parent = match self.inodes.get(&ino) {
+            Some(inode) if inode.lookups == 0 && inode.handles == 0 && !inode.local => inode.parent,
+            _ => return,
+        };
+        // local-only files can't be fetched again
+        let has_local_children = self.inodes[&ino]
+            .children
+            .values()
+            .any(|child| self.inodes.get(child).map(|i| i.local).unwrap_or(false));
+        if has_local_children {
+            return;
+        }
+        let name = self.files.get(&ino).map(|file| OsString::from(&file.file.name));
+        self.evict(ino);
+        if let Some(parent) = self.inodes.get_mut(&parent) {
+            if let Some(name) = name {
+                parent.children.remove(&name);
+            }
+            // the listing has to be loaded again for a lookup of the evicted name
+            parent.listed = false;
+        }
+    }
+
+    fn evict(&mut self, ino: u64) {
+        debug!(inode = ino, "evict inode");
+        self.strm_data.remove(&ino);
+        if let Some(file) = self.files.remove(&ino) {
+            if self.paths.get(&file.path) == Some(&ino) {
+                self.paths.remove(&file.path);
+            }
+        }
+        if let Some(inode) = self.inodes.remove(&ino) {
+            // children only known from a listing go with their directory
+            for child in inode.children.values() {
+                if let Some(child_inode) = self.inodes.get(child) {
+                    if child_inode.lookups == 0 && child_inode.handles == 0 {
+                        self.evict(*child);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Rough number of bytes used by the inode table
+    fn memory_usage(&self) -> usize {
+        let files: usize = self
+            .files
+            .values()
+            .map(|file| {
+                size_of::<(u64, AlistFile)>()
+                    + file.path.len()
+                    + file.file.name.len()
+                    + file.file.sign.len()
+                    + file.file.thumb.len()
+                    + file.file.hashinfo.len()
+            })
+            .sum();
+        let inodes: usize = self
+            .inodes
+            .values()
+            .map(|inode| {
+                size_of::<(u64, Inode)>()
+                    + inode
+                        .children
+                        .keys()
+                        .map(|name| size_of::<(OsString, u64)>() + name.len())
+                        .sum::<usize>()
+            })
+            .sum();
+        let paths: usize = self
+            .paths
+            .keys()
+            .map(|path| size_of::<(String, u64)>() + path.len())
+            .sum();
+        files + inodes + paths
+    }
+
+    fn report_memory(&mut self) {
+        if self.last_memory_report.elapsed() < MEMORY_REPORT_INTERVAL {
+            return;
+        }
+        self.last_memory_report = Instant::now();
+        info!(
+            inodes = self.inodes.len(),
+            files = self.files.len(),
+            bytes = self.memory_usage(),
+            "inode table size"
+        );
+    }
+
+    /// Cache lifetimes for the file at `ino`
+    fn ttl_of(&self, ino: u64) -> Ttl {
+        let path = self.files.get(&ino).map(|file| file.path.as_str()).unwrap_or("/");
+        self.config.ttl.get(path)
+    }
+
+    /// Used and total bytes of the drive
+    fn quota(&mut self) -> (u64, u64) {
+        if self.config.capacity > 0 {
+            return (0, self.config.capacity);
+        }
+        if let Some((fetched_at, quota)) = self.quota {
+            if fetched_at.elapsed() < STATFS_CACHE_TTL {
+                return quota;
+            }
+        }
+        let quota = match self.drive.get_quota() {
+            Ok(quota) => quota,
+            Err(err) => {
+                warn!(error = %err, "get quota failed, set --capacity to report a fixed size");
+                (0, UNKNOWN_CAPACITY)
+            }
+        };
+        self.quota = Some((Instant::now(), quota));
+        quota
+    }
+
+    /// Fail mutating operations right away on a read-only mount
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.config.read_only {
+            Err(Error::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
     /// Next file handler
     fn next_fh(&mut self) -> u64 {
         self.next_fh = self.next_fh.wrapping_add(1);
@@ -127,8 +493,8 @@ impl AlistDriveFileSystem {
     }
 
 
-    fn allocate_next_file_handle(&self, read: bool, write: bool) -> u64 {
-        let mut fh = self.next_fh.wrapping_add(1);
+    fn allocate_next_file_handle(&mut self, read: bool, write: bool) -> u64 {
+        let mut fh = self.next_fh();
         // Assert that we haven't run out of file handles
         assert!(fh < FILE_HANDLE_WRITE_BIT && fh < FILE_HANDLE_READ_BIT);
         if read {
@@ -143,44 +509,124 @@ impl AlistDriveFileSystem {
 
     fn init(&mut self) -> Result<(), Error> {
         let mut root_file = AlistFile::new_root();
-        // let (used_size, _) = self.drive.get_quota().map_err(|_| Error::ApiCallFailed)?;
         // root_file.size = used_size.to_string();
         let root_inode = Inode::new(0);
         self.inodes.insert(FUSE_ROOT_ID, root_inode);
+        self.paths.insert(root_file.path.clone(), FUSE_ROOT_ID);
         self.files.insert(FUSE_ROOT_ID, root_file);
         Ok(())
     }
 
     fn lookup(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr, Error> {
-        let file_name = name.to_string_lossy().to_string();
-        debug!(file_name = file_name, "lookup for macos special file");
-
-        if file_name == ".DS_Store" || file_name.starts_with("._") || file_name.starts_with(".") {
-            error!(file_name = file_name, "lookup for macos special file");
+        let parent_inode = self.inodes.get(&parent).ok_or(Error::ParentNotFound)?;
+        if let Some(inode) = parent_inode.children.get(name) {
+            let file = self.files.get(inode).ok_or(Error::NoEntry)?;
+            return Ok(file.to_file_attr(*inode, &self.config.permissions));
+        }
+        if parent_inode.listed || self.config.ignore.is_hidden(&name.to_string_lossy()) {
             return Err(Error::ChildNotFound);
         }
-
-        let mut parent_inode = self
-            .inodes
-            .get(&parent)
-            .ok_or(Error::ParentNotFound)?
-            .clone();
-        if parent_inode.children.is_empty() {
-            // Parent inode isn't loaded yet
-            debug!(parent = parent, "readdir missing parent in lookup");
-            self.readdir(parent, 0)?;
-            parent_inode = self
-                .inodes
-                .get(&parent)
-                .ok_or(Error::ParentNotFound)?
-                .clone();
-        }
-        let inode = parent_inode
-            .children
-            .get(name)
-            .ok_or(Error::ChildNotFound)?;
+        let parent_path = self.files.get(&parent).ok_or(Error::ParentNotFound)?.path.clone();
+        let listing_cached = self.dir_cache.get(&parent_path).is_some()
+            || self.dir_index.as_ref().map(|index| index.contains(&parent_path)).unwrap_or(false);
+        let path = child_path(&parent_path, name);
+        if self.negative_cache.contains(&path) {
+            debug!(path = %path, "negative cache hit");
+            return Err(Error::ChildNotFound);
+        }
+        // `.strm` names don't exist on the server, they only come from listings
+        if !listing_cached && self.config.strm.is_none() {
+            // Resolve just this child instead of listing a possibly huge parent
+            match self.drive.get_file(&path) {
+                Ok(Some(file)) => return Ok(self.insert_child(parent, file)),
+                Ok(None) => {
+                    self.negative_cache.insert(path);
+                    return Err(Error::ChildNotFound);
+                }
+                Err(err) => {
+                    debug!(path = %path, error = %err, "get file failed, list parent instead");
+                }
+            }
+        }
+        debug!(parent = parent, "readdir missing parent in lookup");
+        self.readdir(parent, 0)?;
+        let parent_inode = self.inodes.get(&parent).ok_or(Error::ParentNotFound)?;
+        let inode = match parent_inode.children.get(name) {
+            Some(inode) => inode,
+            None => {
+                self.negative_cache.insert(path);
+                return Err(Error::ChildNotFound);
+            }
+        };
         let file = self.files.get(inode).ok_or(Error::NoEntry)?;
-        Ok(file.to_file_attr(*inode))
+        Ok(file.to_file_attr(*inode, &self.config.permissions))
+    }
+
+    /// Add a single child found without listing its parent, the parent is
+    /// not marked as listed
+    fn insert_child(&mut self, parent: u64, file: AlistFile) -> FileAttr {
+        let ino = self.inode_for_path(&file.path);
+        let attr = file.to_file_attr(ino, &self.config.permissions);
+        let name = OsString::from(&file.file.name);
+        self.files.insert(ino, file);
+        self.inodes.entry(ino).or_insert_with(|| Inode::new(parent)).parent = parent;
+        if let Some(parent_inode) = self.inodes.get_mut(&parent) {
+            parent_inode.add_child(name, ino);
+        }
+        attr
+    }
+
+    /// List a directory, from the directory cache if possible
+    fn list_dir(&self, path: &String) -> Result<Vec<AlistFile>, Error> {
+        if let Some(files) = self.dir_cache.get(path) {
+            debug!(path = %path, "dir cache hit");
+            return Ok(files);
+        }
+        if let Some(files) = self.dir_index.as_ref().and_then(|index| index.get(path)) {
+            debug!(path = %path, "dir index hit");
+            return Ok(files);
+        }
+        let files = match self.drive.list_all(path) {
+            Ok(files) => files,
+            Err(err) => {
+                let stale = self.dir_index.as_ref().and_then(|index| index.stale(path));
+                return match stale {
+                    Some(files) => {
+                        warn!(path = %path, error = %err, "list dir failed, serving stale dir index");
+                        Ok(files)
+                    }
+                    None => Err(Error::ApiCallFailed),
+                };
+            }
+        };
+        if let Some(index) = &self.dir_index {
+            index.save(path, &files);
+        }
+        self.dir_cache.insert(path.clone(), files.clone());
+        Ok(files)
+    }
+
+    /// Drop the cached listing of a directory after its content changed
+    fn invalidate_dir(&self, ino: u64) {
+        if let Some(file) = self.files.get(&ino) {
+            self.invalidate_path(&file.path);
+        }
+    }
+
+    fn invalidate_path(&self, path: &str) {
+        self.dir_cache.invalidate(Path::new(path));
+        self.negative_cache.invalidate_children(path);
+        if let Some(index) = &self.dir_index {
+            index.remove(path);
+        }
+    }
+
+    /// Drop the cached listings of a removed or moved directory and below
+    fn invalidate_tree(&self, path: &str) {
+        self.dir_cache.invalidate_tree(Path::new(path));
+        if let Some(index) = &self.dir_index {
+            index.remove_tree(path);
+        }
     }
 
     fn readdir(&mut self, ino: u64, offset: i64) -> Result<Vec<(u64, FileType, String)>, Error> {
@@ -191,11 +637,9 @@ impl AlistDriveFileSystem {
             entries.push((ino, FileType::Directory, ".".to_string()));
             entries.push((inode.parent, FileType::Directory, String::from("..")));
             let file = self.files.get(&ino).ok_or(Error::NoEntry)?;
-            let parent_file_id = &file.path;
-            let files = self
-                .drive
-                .list_all(parent_file_id)
-                .map_err(|_| Error::ApiCallFailed)?;
+            let parent_file_id = file.path.clone();
+            let files = self.list_dir(&parent_file_id)?;
+            self.negative_cache.invalidate_children(&parent_file_id);
             debug!(
                 inode = ino,
                 "total {} files in directory {}",
@@ -204,14 +648,59 @@ impl AlistDriveFileSystem {
             );
 
             // 删除所有旧的child 重新添加
-            let mut to_remove = inode.children.keys().cloned().collect::<Vec<_>>();
+            // local-only children are not in the listing but stay
+            // and so do files written but not uploaded yet
+            let mut to_remove = inode
+                .children
+                .iter()
+                .filter(|(_, child)| !self.inodes.get(child).map(|i| i.local).unwrap_or(false))
+                .filter(|(_, child)| !self.is_uploading(**child))
+                .map(|(name, _)| name.clone())
+                .collect::<Vec<_>>();
+            let collisions = match &self.config.strm {
+                Some(_) => strm::collisions(&files),
+                None => HashSet::new(),
+            };
             for file in &files {
+                if self.config.ignore.is_hidden(&file.file.name) {
+                    continue;
+                }
+                let strm = self.config.strm.as_ref().and_then(|strm| strm.to_strm(file, &collisions));
+                let file = match &strm {
+                    Some((strm_file, _)) => strm_file,
+                    None => file,
+                };
                 let name = OsString::from(file.file.name.clone());
                 to_remove.retain(|n| n != &name);
-                let new_inode = self.next_inode();
-                inode.add_child(name, new_inode);
-                self.files.insert(new_inode, file.clone());
-                self.inodes.entry(new_inode).or_insert_with(|| Inode::new(ino));
+                let child_ino = self.inode_for_path(&file.path);
+                if self.is_uploading(child_ino) {
+                    // the listing still has the content before our writes
+                    inode.add_child(name, child_ino);
+                    continue;
+                }
+                let replaced = self
+                    .files
+                    .get(&child_ino)
+                    .map(|old| old.file.is_dir != file.file.is_dir || *old.file.created != *file.file.created)
+                    .unwrap_or(false);
+                inode.add_child(name, child_ino);
+                self.files.insert(child_ino, file.clone());
+                let child = self.inodes.entry(child_ino).or_insert_with(|| Inode::new(ino));
+                child.parent = ino;
+                if replaced {
+                    debug!(inode = child_ino, path = %file.path, "file replaced");
+                    child.generation += 1;
+                    child.children.clear();
+                    child.listed = false;
+                }
+                match strm {
+                    Some((_, content)) => {
+                        self.strm_data.insert(child_ino, content);
+                    }
+                    None => {
+                        self.strm_data.remove(&child_ino);
+                    }
+                }
 
                 //  如果存在名称则删除？
                 // if inode.children.contains_key(&name) {
@@ -232,12 +721,17 @@ impl AlistDriveFileSystem {
                 for name in to_remove {
                     if let Some(ino_remove) = inode.children.remove(&name) {
                         debug!(inode = ino_remove, name = %Path::new(&name).display(), "remove outdated inode");
-                        self.files.remove(&ino_remove);
+                        if let Some(file) = self.files.remove(&ino_remove) {
+                            self.paths.remove(&file.path);
+                        }
                         self.inodes.remove(&ino_remove);
+                        self.strm_data.remove(&ino_remove);
                     }
                 }
             }
+            inode.listed = true;
             self.inodes.insert(ino, inode.clone());
+            self.report_memory();
         }
 
         for child_ino in inode.children.values().skip(offset as usize) {
@@ -253,178 +747,161 @@ impl AlistDriveFileSystem {
         Ok(entries)
     }
 
-    fn read(&mut self, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Bytes, Error> {
+    /// A spool holding the first `len` bytes of a file, copied from a handle
+    /// already writing it if there is one
+    fn stage(&self, ino: u64, len: u64) -> Result<Spool, Error> {
         let file = self.files.get(&ino).ok_or(Error::NoEntry)?;
-        debug!(inode = ino, name = %file.file.name, fh = fh, offset = offset, size = size, "read");
-        if offset >= file.file.size.try_into().unwrap() {
-            return Ok(Bytes::new());
-        }
-        let size = std::cmp::min(size, file.file.size.saturating_sub(offset as u64) as u32);
-        self.file_cache.read(fh, offset, size)
+        let res = match self.uploads.iter().find(|((i, _), _)| *i == ino) {
+            Some((_, state)) => self.uploader.stage_from(file, &state.spool, len),
+            None => self.uploader.stage(file, len),
+        };
+        res.map_err(|err| {
+            error!(path = %file.path, error = %err, "stage file failed");
+            Error::SpoolFailed
+        })
     }
 
-
-
-    fn prepare_for_upload(&mut self,ino: u64, fh: u64) -> Result<bool, Error> {
-        debug!(chunk_count=self.upload_state.chunk_count, " prepare_for_upload upload_state.chunk_count");
-        let mut file = match self.files.get(&ino) {
-            Some(file) => file.clone(),
-            None => {
-                error!(inode = ino, "file not found");
-                return Err(Error::NoEntry)
+    /// Let `fh` write an existing file, emptying it first if `truncate`
+    fn open_for_write(&mut self, ino: u64, fh: u64, truncate: bool) -> Result<(), Error> {
+        if let Some(buf) = self.local_data.get_mut(&ino) {
+            if truncate {
+                buf.clear();
             }
-        };
-
-        if !file.path.is_empty() {
-            return Ok(false);
-        }
-
-
-        // 忽略 macOS 上的一些特殊文件
-        if file.file.name == ".DS_Store" || file.file.name.starts_with(".") {
-            return Ok(false);
+        } else {
+            let file = self.files.get(&ino).ok_or(Error::NoEntry)?;
+            let len = if truncate { 0 } else { file.file.size };
+            let spool = self.stage(ino, len)?;
+            // truncating changes the file even if nothing is written
+            self.uploads.insert((ino, fh), UploadState { spool, dirty: truncate, removed: false });
         }
-
-
-        if self.upload_state.chunk_count == 0 {
-            let size = self.upload_state.size;
-            debug!(file_id=file.path, name=%file.file.name, size=size, "prepare_for_upload");
-            if !file.path.is_empty() {
-                return Ok(false);
-            }
-            // TODO: create parent folders?
-            debug!("prepare_for_upload after upload_state.chunk_count==0");
-            let upload_buffer_size = self.upload_buffer_size as u64;
-            let chunk_count =
-                size / upload_buffer_size + if size % upload_buffer_size != 0 { 1 } else { 0 };
-
-            debug!(chunk_count=chunk_count, "prepare_for_upload chunk_count");
-
-
-            self.upload_state.chunk_count = chunk_count;
-            debug!("uploading {} ({} bytes)...", file.file.name, size);
-            if size>0 {
-                let hash = file.clone().file.hashinfo;
-                let res = self
-                    .drive
-                    .create_file_with_proof(&file.file.name, &file.path, &hash, size);
-
-                
-                let upload_response = match res {
-                    Ok(upload_response_info) => upload_response_info,
-                    Err(err) => {
-                        error!(file_name = file.file.name, error = %err, "create file with proof failed");
-                        return Ok(false);
-                    }
-                };
-
-              
-                debug!(file_name = upload_response.file.file.name, "upload response name");
-                let oss_args = OssArgs {
-                    bucket: upload_response.resumable.params.bucket.to_string(),
-                    key: upload_response.resumable.params.key.to_string(),
-                    endpoint: upload_response.resumable.params.endpoint.to_string(),
-                    access_key_id: upload_response.resumable.params.access_key_id.to_string(),
-                    access_key_secret: upload_response.resumable.params.access_key_secret.to_string(),
-                    security_token: upload_response.resumable.params.security_token.to_string(),
-                };
-                self.upload_state.oss_args = Some(oss_args);
-    
-                let oss_args = self.upload_state.oss_args.as_ref().unwrap();
-                let pre_upload_info = self.drive.get_pre_upload_info(&oss_args);
-                if let Err(err) = pre_upload_info {
-                    error!(file_name = file.file.name, error = %err, "get pre upload info failed");
-                    return Ok(false);
-                }
-               
-                self.upload_state.upload_id = match pre_upload_info {
-                    Ok(upload_id) => upload_id,
-                    Err(err) => {
-                        error!(file_name = file.file.name, error = %err, "get pre upload info failed");
-                        return Ok(false);
-                    }
-                };
-                debug!(file_name = file.file.name, upload_id = %self.upload_state.upload_id, "pre upload info get upload_id success");
+        if truncate {
+            if let Some(file) = self.files.get_mut(&ino) {
+                file.file.size = 0;
             }
         }
-        Ok(true)
+        Ok(())
     }
 
-
-    fn maybe_upload_chunk(&mut self,remaining: bool,ino: u64, fh: u64)-> Result<(), Error>{
-        let chunk_size = if remaining {
-            // last chunk size maybe less than upload_buffer_size
-            self.upload_state.buffer.remaining()
+    /// Cut off or extend a file, written back like any other write
+    fn truncate(&mut self, ino: u64, fh: Option<u64>, size: u64) -> Result<(), Error> {
+        if self.strm_data.contains_key(&ino) {
+            return Err(Error::NotSupported);
+        }
+        if let Some(buf) = self.local_data.get_mut(&ino) {
+            buf.resize(size as usize, 0);
         } else {
-            self.upload_buffer_size
-        };
-        //let chunk_size = self.upload_state.buffer.remaining();
-        let current_chunk = self.upload_state.chunk;
-        debug!(chunk_size=chunk_size,"chunk_size is");
-        debug!(upload_state_buffer_remaining=self.upload_state.buffer.remaining(),"buffer remaining is");
-        debug!(current_chunk=current_chunk,"current_chunk is");
-        debug!(chunk_count=self.upload_state.chunk_count, "chunk_count is");
-
-        if chunk_size > 0
-        && self.upload_state.buffer.remaining() >= chunk_size
-        && current_chunk <= self.upload_state.chunk_count
-        {
-            debug!("maybe_upload_chunk after chunk_size>0");
-            let file = self.files.get(&ino).ok_or(Error::NoEntry)?;
-            let chunk_data = self.upload_state.buffer.split_to(chunk_size);
-
-            let upload_data = chunk_data.freeze();
-            let oss_args = match self.upload_state.oss_args.as_ref() {
-                Some(oss_args) => oss_args,
-                None => {
-                    error!(file_name = %file.file.name, "获取文件上传信息错误");
-                    return Err(Error::UploadFailed);
+            // preferably the handle it was truncated through
+            let key = fh
+                .map(|fh| (ino, fh))
+                .filter(|key| self.uploads.contains_key(key))
+                .or_else(|| self.uploads.keys().find(|(i, _)| *i == ino).copied());
+            match key.and_then(|key| self.uploads.get_mut(&key)) {
+                Some(state) => {
+                    state.spool.set_len(size).map_err(|_| Error::SpoolFailed)?;
+                    state.dirty = true;
                 }
-            };
-            let res = self.drive.upload_chunk(file,oss_args,&self.upload_state.upload_id,current_chunk,upload_data.clone());
-            
-            let part = match res {
-                Ok(part) => part,
-                Err(err) => {
-                    error!(file_name = %file.file.name, error = %err, "上传分片失败，无法获取ETag");
-                    return Err(Error::UploadFailed);
+                None => {
+                    let spool = self.stage(ino, size)?;
+                    let path = &self.files.get(&ino).ok_or(Error::NoEntry)?.path;
+                    self.uploader.enqueue(path, spool);
                 }
-            };
-                
-            debug!(chunk_count = %self.upload_state.chunk_count, current_chunk=current_chunk, "upload chunk info");
-            self.upload_state.upload_tags.Part.push(part);
-
-             
-            if current_chunk == self.upload_state.chunk_count{
-                debug!(file_name = %file.file.name, "upload finished");
-                let mut buffer = Vec::new();
-                let mut ser = XmlSerializer::with_root(Writer::new_with_indent(&mut buffer, b' ', 4), Some("CompleteMultipartUpload"));
-                self.upload_state.upload_tags.serialize(&mut ser).unwrap();
-                let upload_tags = String::from_utf8(buffer).unwrap();
-                self.drive.complete_upload(file,upload_tags,oss_args,&self.upload_state.upload_id);
-                self.upload_state = UploadState::default();
-                return Ok(());
-            }
-            self.upload_state.chunk += 1;
+            }
+        }
+        if let Some(file) = self.files.get_mut(&ino) {
+            file.file.size = size;
         }
         Ok(())
     }
 
+    /// Written through an open handle or waiting to be uploaded
+    fn is_uploading(&self, ino: u64) -> bool {
+        self.uploads.iter().any(|((i, _), state)| *i == ino && !state.removed)
+            || self
+                .files
+                .get(&ino)
+                .map(|file| self.uploader.is_pending(&file.path))
+                .unwrap_or(false)
+    }
 
+    fn set_pin(&mut self, ino: u64, value: &[u8]) -> Result<(), Error> {
+        let file = self.files.get(&ino).ok_or(Error::NoEntry)?;
+        let pinner = self.file_cache.pinner().ok_or(Error::NotSupported)?;
+        match value.trim_ascii() {
+            b"1" => pinner.pin(file),
+            b"0" => pinner.unpin(&file.path),
+            _ => return Err(Error::InvalidArgument),
+        }
+        Ok(())
+    }
 
+    /// Files that are uploaded when written, unlike directories, local-only
+    /// and `.strm` files
+    fn has_upload_status(&self, ino: u64) -> bool {
+        let is_dir = self.files.get(&ino).map(|file| file.file.is_dir).unwrap_or(true);
+        !is_dir && !self.is_local(ino) && !self.strm_data.contains_key(&ino)
+    }
 
-    
+    fn get_xattr(&self, ino: u64, name: &OsStr) -> Result<Vec<u8>, Error> {
+        let file = self.files.get(&ino).ok_or(Error::NoEntry)?;
+        if name == UPLOAD_STATUS_XATTR && self.has_upload_status(ino) {
+            let writing = self.uploads.iter().any(|((i, _), state)| *i == ino && state.dirty);
+            if writing {
+                return Ok(b"writing".to_vec());
+            }
+            return Ok(self.uploader.status(&file.path).into_bytes());
+        }
+        let pinner = self.file_cache.pinner().ok_or(Error::NoAttribute)?;
+        if name == PIN_XATTR {
+            let pinned = if pinner.is_pinned(&file.path) { "1" } else { "0" };
+            Ok(pinned.as_bytes().to_vec())
+        } else if name == PIN_STATUS_XATTR {
+            Ok(pinner.status(&file.path).into_bytes())
+        } else {
+            Err(Error::NoAttribute)
+        }
+    }
+
+    fn read(&mut self, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Bytes, Error> {
+        if let Some(data) = self.local_data.get(&ino).or_else(|| self.strm_data.get(&ino)) {
+            let start = (offset as usize).min(data.len());
+            let end = (start + size as usize).min(data.len());
+            return Ok(Bytes::copy_from_slice(&data[start..end]));
+        }
+        // written but not uploaded yet, preferably through this handle
+        let spool = self
+            .uploads
+            .get(&(ino, fh))
+            .or_else(|| self.uploads.iter().find(|((i, _), _)| *i == ino).map(|(_, state)| state));
+        if let Some(state) = spool {
+            return state.spool.read(offset as u64, size).map_err(|_| Error::SpoolFailed);
+        }
+        let file = self.files.get(&ino).ok_or(Error::NoEntry)?;
+        if let Some(data) = self.uploader.read_pending(&file.path, offset as u64, size) {
+            return data.map_err(|_| Error::SpoolFailed);
+        }
+        debug!(inode = ino, name = %file.file.name, fh = fh, offset = offset, size = size, "read");
+        if offset >= file.file.size.try_into().unwrap() {
+            return Ok(Bytes::new());
+        }
+        let size = std::cmp::min(size, file.file.size.saturating_sub(offset as u64) as u32);
+        self.file_cache.read(fh, offset, size)
+    }
 }
 
 impl Filesystem for AlistDriveFileSystem {
     fn init(
         &mut self,
         _req: &Request<'_>,
-        _config: &mut fuser::KernelConfig,
+        config: &mut fuser::KernelConfig,
     ) -> Result<(), libc::c_int> {
         if let Err(e) = self.init() {
             return Err(e.into());
         }
+        // get O_TRUNC in open instead of open and setattr(size=0), so
+        // overwriting a file doesn't download it first
+        if let Err(unsupported) = config.add_capabilities(fuser::consts::FUSE_ATOMIC_O_TRUNC) {
+            warn!(capabilities = unsupported, "kernel doesn't support atomic O_TRUNC");
+        }
         Ok(())
     }
 
@@ -433,21 +910,88 @@ impl Filesystem for AlistDriveFileSystem {
         // 忽略 macOS 上的一些特殊文件
         debug!(parent = parent, name = %dirname.display(), "lookup");
         match self.lookup(parent, name) {
-            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Ok(attr) => {
+                let generation = self.generation(attr.ino);
+                self.remember(attr.ino);
+                reply.entry(&self.ttl_of(attr.ino).entry, &attr, generation)
+            }
+            Err(Error::ChildNotFound) if !self.negative_cache.ttl().is_zero() => {
+                // an entry with inode 0 is cached by the kernel as a negative dentry
+                reply.entry(&self.negative_cache.ttl(), &negative_entry_attr(), 0)
+            }
             Err(e) => reply.error(e.into()),
         }
     }
 
+    fn statfs(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyStatfs) {
+        debug!(inode = ino, "statfs");
+        let (used, total) = self.quota();
+        let blocks = total / STATFS_BLOCK_SIZE;
+        let bfree = total.saturating_sub(used) / STATFS_BLOCK_SIZE;
+        let files = self.files.len() as u64;
+        reply.statfs(
+            blocks,
+            bfree,
+            bfree,
+            STATFS_MAX_FILES,
+            STATFS_MAX_FILES.saturating_sub(files),
+            STATFS_BLOCK_SIZE as u32,
+            255,
+            STATFS_BLOCK_SIZE as u32,
+        );
+    }
+
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        debug!(inode = ino, nlookup = nlookup, "forget");
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.lookups = inode.lookups.saturating_sub(nlookup);
+        }
+        self.maybe_evict(ino);
+        self.report_memory();
+    }
+
     fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
         if let Some(file) = self.files.get(&ino) {
             debug!(inode = ino, name = %file.file.name, "getattr");
-            reply.attr(&TTL, &file.to_file_attr(ino))
+            reply.attr(&self.ttl_of(ino).attr, &file.to_file_attr(ino, &self.config.permissions))
         } else {
             debug!(inode = ino, "getattr");
             reply.error(libc::ENOENT);
         }
     }
 
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        debug!(inode = ino, size = ?size, "setattr");
+        // only the size can be changed, everything else is reported unchanged
+        if let Some(size) = size {
+            if let Err(e) = self.check_writable().and_then(|_| self.truncate(ino, fh, size)) {
+                reply.error(e.into());
+                return;
+            }
+        }
+        match self.files.get(&ino) {
+            Some(file) => reply.attr(&self.ttl_of(ino).attr, &file.to_file_attr(ino, &self.config.permissions)),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
     fn readdir(
         &mut self,
         _req: &Request<'_>,
@@ -475,22 +1019,116 @@ impl Filesystem for AlistDriveFileSystem {
         }
     }
 
-    fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+    fn setxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        _flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        if let Err(e) = self.check_writable() {
+            reply.error(e.into());
+            return;
+        }
+        debug!(inode = ino, name = ?name, "setxattr");
+        if name != PIN_XATTR {
+            reply.error(libc::ENOTSUP);
+            return;
+        }
+        // there is no such file on the server to warm
+        if self.strm_data.contains_key(&ino) {
+            reply.error(libc::EACCES);
+            return;
+        }
+        match self.set_pin(ino, value) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn getxattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        debug!(inode = ino, name = ?name, "getxattr");
+        match self.get_xattr(ino, name) {
+            Ok(value) if size == 0 => reply.size(value.len() as u32),
+            Ok(value) if value.len() > size as usize => reply.error(libc::ERANGE),
+            Ok(value) => reply.data(&value),
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        debug!(inode = ino, "listxattr");
+        if !self.files.contains_key(&ino) {
+            reply.error(libc::ENOENT);
+            return;
+        }
+        let mut names = Vec::new();
+        if self.file_cache.pinner().is_some() {
+            for name in [PIN_XATTR, PIN_STATUS_XATTR] {
+                names.extend_from_slice(name.as_bytes());
+                names.push(0);
+            }
+        }
+        if self.has_upload_status(ino) {
+            names.extend_from_slice(UPLOAD_STATUS_XATTR.as_bytes());
+            names.push(0);
+        }
+        if size == 0 {
+            reply.size(names.len() as u32);
+        } else if names.len() > size as usize {
+            reply.error(libc::ERANGE);
+        } else {
+            reply.data(&names);
+        }
+    }
+
+    fn removexattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        if let Err(e) = self.check_writable() {
+            reply.error(e.into());
+            return;
+        }
+        debug!(inode = ino, name = ?name, "removexattr");
+        if name != PIN_XATTR {
+            reply.error(libc::ENOTSUP);
+            return;
+        }
+        match self.set_pin(ino, b"0") {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.into()),
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
         debug!(inode = ino, "open");
-        if let Some((file_id, file_name, file_size)) = self
-            .files
-            .get(&ino)
-            .map(|f| (f.path.clone(), f.file.name.clone(), f.file.size))
-        {
-            debug!(inode = ino, name = %file_name, "open file");
-            // 忽略 macOS 上的一些特殊文件
-            if file_name == ".DS_Store" || file_name.starts_with("._") {
-                //reply.error(libc::ENOENT);
+        if flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0 {
+            if let Err(e) = self.check_writable() {
+                reply.error(e.into());
                 return;
             }
-
+            if self.strm_data.contains_key(&ino) {
+                reply.error(libc::EACCES);
+                return;
+            }
+        }
+        if let Some(file) = self.files.get(&ino).cloned() {
+            let file_name = &file.file.name;
+            debug!(inode = ino, name = %file_name, "open file");
             let fh = self.next_fh();
-            self.file_cache.open(fh, file_id, file_size);
+            if flags & libc::O_ACCMODE != libc::O_RDONLY {
+                if let Err(e) = self.open_for_write(ino, fh, flags & libc::O_TRUNC != 0) {
+                    reply.error(e.into());
+                    return;
+                }
+            }
+            if !self.is_local(ino) && !self.strm_data.contains_key(&ino) {
+                self.file_cache.open(fh, &file);
+            }
+            if let Some(inode) = self.inodes.get_mut(&ino) {
+                inode.handles += 1;
+            }
             reply.opened(fh, 0);
         } else {
             debug!(inode = ino, "open file");
@@ -511,6 +1149,36 @@ impl Filesystem for AlistDriveFileSystem {
     ) {
         debug!(inode = ino, fh = fh, "release file");
         self.file_cache.release(fh);
+        if let Some(state) = self.uploads.remove(&(ino, fh)) {
+            match self.files.get(&ino) {
+                Some(file) if state.dirty && !state.removed => self.uploader.enqueue(&file.path, state.spool),
+                _ => state.spool.discard(),
+            }
+        }
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.handles = inode.handles.saturating_sub(1);
+        }
+        self.maybe_evict(ino);
+        reply.ok();
+    }
+
+    fn fsync(&mut self, _req: &Request<'_>, ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        debug!(inode = ino, fh = fh, "fsync");
+        let path = match self.files.get(&ino) {
+            Some(file) => file.path.clone(),
+            None => {
+                reply.error(libc::ENOENT);
+                return;
+            }
+        };
+        if let Some(state) = self.uploads.get_mut(&(ino, fh)).filter(|state| state.dirty) {
+            if let Err(err) = self.uploader.upload(&path, &state.spool) {
+                error!(path = %path, error = %err, "upload failed");
+                reply.error(Error::UploadFailed.into());
+                return;
+            }
+            state.dirty = false;
+        }
         reply.ok();
     }
 
@@ -543,6 +1211,10 @@ impl Filesystem for AlistDriveFileSystem {
         flags: u32,
         reply: ReplyEmpty,
     ) {
+        if let Err(e) = self.check_writable() {
+            reply.error(e.into());
+            return;
+        }
         let file = match self.lookup(parent, name) {
             Ok(attrs) => attrs,
             Err(error_code) => {
@@ -552,10 +1224,59 @@ impl Filesystem for AlistDriveFileSystem {
         };
 
         debug!(flags = flags, name=name.to_string_lossy().to_string(), "rename");
+        // `.strm` files stand in for videos, the server doesn't know them
+        if self.strm_data.contains_key(&file.ino) {
+            reply.error(libc::EACCES);
+            return;
+        }
+        if self.config.ignore.is_refused(&new_name.to_string_lossy()) {
+            reply.error(libc::EPERM);
+            return;
+        }
+        let new_path = match self.files.get(&new_parent) {
+            Some(new_parent_file) => child_path(&new_parent_file.path, new_name),
+            None => {
+                reply.error(libc::ENOENT);
+                return;
+            }
+        };
+        if self.is_local(file.ino) {
+            // e.g. an editor saving through a temporary file, the result
+            // is a real file that has to reach the server
+            let uploaded = file.kind != FileType::Directory
+                && !self.config.ignore.is_local(&new_name.to_string_lossy());
+            if uploaded {
+                if let Err(e) = self.upload_local(file.ino, &new_path) {
+                    reply.error(e.into());
+                    return;
+                }
+                self.invalidate_dir(new_parent);
+            }
+            self.renamed(file.ino, parent, name, new_parent, new_name);
+            reply.ok();
+            return;
+        }
         let file_id = self.files.get(&file.ino).unwrap().path.clone();
+        let pending = self.is_uploading(file.ino);
+        // queued uploads follow the file to its new name
+        self.uploader.rename(&file_id, &new_path);
+        if pending && matches!(self.drive.get_file(&file_id), Ok(None)) {
+            // never uploaded, there is nothing to rename on the server
+            debug!(from = %file_id, to = %new_path, "rename file not uploaded yet");
+            self.invalidate_dir(parent);
+            self.invalidate_dir(new_parent);
+            self.renamed(file.ino, parent, name, new_parent, new_name);
+            reply.ok();
+            return;
+        }
+        // the old path of a directory and everything below it is gone
+        self.invalidate_tree(&file_id);
+        self.invalidate_dir(parent);
+        self.invalidate_dir(new_parent);
         if parent == new_parent {
             let res:AlistFile = match self.drive.rename_file(&file_id, &new_name.to_string_lossy()) {
                 Ok(res) => {
+                     self.renamed(file.ino, parent, name, new_parent, new_name);
                      reply.ok();
                      return;
                 },
@@ -577,9 +1298,10 @@ impl Filesystem for AlistDriveFileSystem {
                     }
                 };
             }
-            let new_parent = self.files.get(&new_parent).unwrap().path.clone();
-            let res:AlistFile = match self.drive.move_file(&file_id, &new_parent) {
+            let new_parent_path = self.files.get(&new_parent).unwrap().path.clone();
+            let res:AlistFile = match self.drive.move_file(&file_id, &new_parent_path) {
                 Ok(res) => {
+                    self.renamed(file.ino, parent, name, new_parent, new_name);
                     reply.ok();
                     return;
                 },
@@ -607,6 +1329,10 @@ impl Filesystem for AlistDriveFileSystem {
         _flags: u32,
         reply: ReplyWrite,
     ) {
+        if let Err(e) = self.check_writable() {
+            reply.error(e.into());
+            return;
+        }
         debug!(
             "copy_file_range() called with src ({}, {}, {}) dest ({}, {}, {}) size={}",
             src_fh, src_inode, src_offset, dest_fh, dest_inode, dest_offset, size
@@ -646,11 +1372,29 @@ impl Filesystem for AlistDriveFileSystem {
         _umask: u32,
         reply: ReplyEntry,
     ) {
+        if let Err(e) = self.check_writable() {
+            reply.error(e.into());
+            return;
+        }
         debug!("mkdir() called with {:?} {:?} {:o}", parent, name, mode);
+        if self.config.ignore.is_refused(&name.to_string_lossy()) {
+            reply.error(libc::EPERM);
+            return;
+        }
         if self.lookup(parent, name).is_ok() {
             reply.error(libc::EEXIST);
             return;
         }
+        if self.config.ignore.is_local(&name.to_string_lossy()) {
+            match self.create_local(parent, name, true) {
+                Ok(attr) => {
+                    self.remember(attr.ino);
+                    reply.entry(&self.ttl_of(attr.ino).entry, &attr, self.generation(attr.ino));
+                }
+                Err(e) => reply.error(e.into()),
+            }
+            return;
+        }
         let parent_file = match self.files.get(&parent).ok_or(Error::NoEntry){
             Ok(file) => file,
             Err(e) => {
@@ -668,16 +1412,28 @@ impl Filesystem for AlistDriveFileSystem {
                 return;
             }
         };
-        let new_dir = new_dir_res.file;
-
-        let new_inode = self.next_inode();
-        let attrs = new_dir.to_file_attr(new_inode);
+        self.invalidate_dir(parent);
+        let mut new_dir = new_dir_res.file;
+        new_dir.path = child_path(&parent_file_id, name);
+
+        let new_inode = self.inode_for_path(&new_dir.path);
+        let attrs = new_dir.to_file_attr(new_inode, &self.config.permissions);
+        self.files.insert(new_inode, new_dir);
+        self.insert_inode(new_inode, Inode::new(parent));
+        if let Some(parent_inode) = self.inodes.get_mut(&parent) {
+            parent_inode.add_child(name.to_os_string(), new_inode);
+        }
 
-        reply.entry(&TTL, &attrs, 0);
+        self.remember(new_inode);
+        reply.entry(&self.ttl_of(new_inode).entry, &attrs, self.generation(new_inode));
     }
 
 
     fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        if let Err(e) = self.check_writable() {
+            reply.error(e.into());
+            return;
+        }
         debug!("rmdir() called with {:?} {:?}", parent, name);
 
         let file = match self.lookup(parent, name) {
@@ -687,10 +1443,17 @@ impl Filesystem for AlistDriveFileSystem {
                 return;
             }
         };
+        if self.is_local(file.ino) {
+            self.remove_local(parent, name, file.ino);
+            reply.ok();
+            return;
+        }
         let file_id = self.files.get(&file.ino).unwrap().path.clone();
 
         let res:TaskResponse = match self.drive.remove_file(&file_id) {
             Ok(res) => {
+                 self.invalidate_tree(&file_id);
+                 self.invalidate_dir(parent);
                  reply.ok();
                  return;
             },
@@ -716,21 +1479,80 @@ impl Filesystem for AlistDriveFileSystem {
         flags: i32,
         reply: ReplyCreate,
     ) {
+        if let Err(e) = self.check_writable() {
+            reply.error(e.into());
+            return;
+        }
         debug!("create() called with {:?} {:?}", parent, name);
-        // 忽略 macOS 上的一些特殊文件
         let file_name = name.to_string_lossy();
-        if file_name == ".DS_Store" || file_name.starts_with("._") {
-            //reply.error(libc::EEXIST);
+        if self.config.ignore.is_refused(&file_name) {
+            reply.error(libc::EPERM);
             return;
         }
 
-        if self.lookup(parent, name).is_ok() {
-            reply.error(libc::EEXIST);
+        if let Ok(attr) = self.lookup(parent, name) {
+            if flags & libc::O_EXCL != 0 || attr.kind == FileType::Directory {
+                reply.error(libc::EEXIST);
+                return;
+            }
+            // `.strm` files stand in for videos and can't be written
+            if self.strm_data.contains_key(&attr.ino) {
+                reply.error(libc::EACCES);
+                return;
+            }
+            // open the existing file instead, replacing it on upload
+            let write = flags & libc::O_ACCMODE != libc::O_RDONLY;
+            let fh = self.allocate_next_file_handle(flags & libc::O_ACCMODE != libc::O_WRONLY, write);
+            if write {
+                if let Err(e) = self.open_for_write(attr.ino, fh, flags & libc::O_TRUNC != 0) {
+                    reply.error(e.into());
+                    return;
+                }
+            }
+            if let Some(file) = self.files.get(&attr.ino).cloned() {
+                if !self.is_local(attr.ino) {
+                    self.file_cache.open(fh, &file);
+                }
+            }
+            self.remember(attr.ino);
+            if let Some(inode) = self.inodes.get_mut(&attr.ino) {
+                inode.handles += 1;
+            }
+            let attr = match self.files.get(&attr.ino) {
+                Some(file) => file.to_file_attr(attr.ino, &self.config.permissions),
+                None => attr,
+            };
+            reply.created(&self.ttl_of(attr.ino).entry, &attr, self.generation(attr.ino), fh, 0);
+            return;
+        }
+
+        if self.config.ignore.is_local(&file_name) {
+            match self.create_local(parent, name, false) {
+                Ok(attr) => {
+                    self.remember(attr.ino);
+                    if let Some(inode) = self.inodes.get_mut(&attr.ino) {
+                        inode.handles += 1;
+                    }
+                    let fh = self.next_fh();
+                    reply.created(&Duration::new(0, 0), &attr, self.generation(attr.ino), fh, 0);
+                }
+                Err(e) => reply.error(e.into()),
+            }
             return;
         }
 
-        let new_file_inode = self.next_inode();
-        let file_inode = Inode::new(new_file_inode);
+        let new_file_inode = match self.files.get(&parent) {
+            Some(parent_file) => {
+                let path = child_path(&parent_file.path, name);
+                self.negative_cache.invalidate(&path);
+                self.inode_for_path(&path)
+            }
+            None => {
+                reply.error(Error::ParentNotFound.into());
+                return;
+            }
+        };
+        let file_inode = Inode::new(parent);
         let mut parent_inode = self.inodes.get(&parent).ok_or(Error::NoEntry).unwrap().clone();
         let parent_file = match self.files.get(&parent).ok_or(Error::NoEntry){
             Ok(file) => file,
@@ -759,13 +1581,12 @@ impl Filesystem for AlistDriveFileSystem {
             hashinfo: file_hash.clone(),
         };
         let file = AlistFile {
-           path:"".to_string(),
+           path: child_path(&parent_file_id, name),
            file:resf,
         };
         self.files.insert(new_file_inode, file.clone());
-        self.inodes.entry(new_file_inode).or_insert_with(|| Inode::new(new_file_inode));
         parent_inode.add_child(name.to_os_string(), new_file_inode);
-        self.inodes.insert(new_file_inode, file_inode);
+        self.insert_inode(new_file_inode, file_inode);
         self.inodes.insert(parent, parent_inode);
 
         let (read, write) = match flags & libc::O_ACCMODE {
@@ -778,12 +1599,27 @@ impl Filesystem for AlistDriveFileSystem {
                 return;
             }
         };
-        let attrs = file.to_file_attr(new_file_inode);
+        let spool = match self.uploader.create_spool() {
+            Ok(spool) => spool,
+            Err(err) => {
+                error!(path = %file.path, error = %err, "create spool failed");
+                reply.error(Error::SpoolFailed.into());
+                return;
+            }
+        };
+        let attrs = file.to_file_attr(new_file_inode, &self.config.permissions);
+        self.remember(new_file_inode);
+        if let Some(inode) = self.inodes.get_mut(&new_file_inode) {
+            inode.handles += 1;
+        }
+        let fh = self.allocate_next_file_handle(read, write);
+        // uploaded on release even if nothing is written
+        self.uploads.insert((new_file_inode, fh), UploadState { spool, dirty: true, removed: false });
         reply.created(
             &Duration::new(0, 0),
             &attrs.into(),
-            0,
-            self.allocate_next_file_handle(read, write),
+            self.generation(new_file_inode),
+            fh,
             0,
         );
 
@@ -791,6 +1627,10 @@ impl Filesystem for AlistDriveFileSystem {
 
 
     fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        if let Err(e) = self.check_writable() {
+            reply.error(e.into());
+            return;
+        }
         debug!("unlink() called with {:?} {:?}", parent, name);
         let file = match self.lookup(parent, name) {
             Ok(file) => file,
@@ -799,12 +1639,39 @@ impl Filesystem for AlistDriveFileSystem {
                 return;
             }
         };
+        // `.strm` files stand in for videos, the server doesn't know them
+        if self.strm_data.contains_key(&file.ino) {
+            reply.error(libc::EACCES);
+            return;
+        }
+        if self.is_local(file.ino) {
+            self.remove_local(parent, name, file.ino);
+            reply.ok();
+            return;
+        }
         let file_id = self.files.get(&file.ino).unwrap().path.clone();
+        // nothing written to it may be uploaded anymore
+        let pending = self.is_uploading(file.ino);
+        for (_, state) in self.uploads.iter_mut().filter(|((i, _), _)| *i == file.ino) {
+            state.removed = true;
+        }
+        self.uploader.cancel(&file_id);
         let res:TaskResponse = match self.drive.remove_file(&file_id) {
             Ok(res) => {
+                 self.invalidate_tree(&file_id);
+                 self.invalidate_dir(parent);
+                 self.unlinked(parent, name, &file_id);
                  reply.ok();
                  return;
             },
+            Err(error_code) if pending => {
+                // never uploaded, so not on the server either
+                debug!(path = %file_id, error = ?error_code, "unlink file not uploaded yet");
+                self.invalidate_dir(parent);
+                self.unlinked(parent, name, &file_id);
+                reply.ok();
+                return;
+            }
             Err(error_code) => {
                 debug!("delete_folder error: {:?}", error_code);
                 reply.error(libc::EFAULT);
@@ -815,19 +1682,9 @@ impl Filesystem for AlistDriveFileSystem {
     }
 
     fn flush(&mut self, _req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        // written data is in the spool, it is uploaded on release or fsync
         debug!("flush() called with {:?} {:?}", ino, fh);
-        match  self.prepare_for_upload(ino, fh) {
-            Ok(true) => {
-                self.maybe_upload_chunk(true, ino, fh);
-                reply.ok();
-            }
-            Ok(false) => {
-                reply.error(libc::ENOENT);
-            }
-            Err(err) => {
-                reply.error(libc::ENOENT);
-            }
-        }
+        reply.ok();
     }
 
     fn write(
@@ -842,31 +1699,74 @@ impl Filesystem for AlistDriveFileSystem {
             lock_owner: Option<u64>,
             reply: ReplyWrite,
         ) {
+        if let Err(e) = self.check_writable() {
+            reply.error(e.into());
+            return;
+        }
         debug!("write() called with {:?} {:?}", offset, data.len());
-        match  self.prepare_for_upload(ino, fh) {
-            Ok(true) => {
-                self.upload_state.buffer.extend_from_slice(&data);
-                let mut upload_size = self.upload_state.size;
-                if data.len() + offset as usize > upload_size as usize {
-                    upload_size = (data.len() + offset as usize) as u64;
-                }
-                self.upload_state.size = upload_size;
-                self.maybe_upload_chunk(false, ino, fh);
-                reply.written(data.len() as u32 );
+        if let Some(buf) = self.local_data.get_mut(&ino) {
+            let end = offset as usize + data.len();
+            if buf.len() < end {
+                buf.resize(end, 0);
             }
-            Ok(false) => {
-                reply.error(libc::ENOENT);
+            buf[offset as usize..end].copy_from_slice(data);
+            let size = buf.len() as u64;
+            if let Some(file) = self.files.get_mut(&ino) {
+                file.file.size = size;
             }
-            Err(err) => {
-                reply.error(libc::ENOENT);
+            reply.written(data.len() as u32);
+            return;
+        }
+        let state = match self.uploads.get_mut(&(ino, fh)) {
+            Some(state) => state,
+            None => {
+                // a handle opened read-only
+                reply.error(libc::EBADF);
+                return;
             }
+        };
+        if let Err(err) = state.spool.write(offset as u64, data) {
+            error!(inode = ino, error = %err, "write spool failed");
+            reply.error(Error::SpoolFailed.into());
+            return;
+        }
+        state.dirty = true;
+        let size = state.spool.size();
+        if let Some(file) = self.files.get_mut(&ino) {
+            file.file.size = size;
         }
+        reply.written(data.len() as u32);
+    }
+
+}
+
+fn negative_entry_attr() -> FileAttr {
+    FileAttr {
+        ino: 0,
+        size: 0,
+        blocks: 0,
+        atime: UNIX_EPOCH,
+        mtime: UNIX_EPOCH,
+        ctime: UNIX_EPOCH,
+        crtime: UNIX_EPOCH,
+        kind: FileType::RegularFile,
+        perm: 0,
+        nlink: 0,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        blksize: 0,
+        flags: 0,
     }
+}
 
+/// Path of the child `name` of the directory at `parent`
+fn child_path(parent: &str, name: &OsStr) -> String {
+    format!("{}/{}", parent.trim_end_matches('/'), name.to_string_lossy())
 }
 
 impl AlistFile {
-    fn to_file_attr(&self, ino: u64) -> FileAttr {
+    fn to_file_attr(&self, ino: u64, permissions: &Permissions) -> FileAttr {
         //let kind = self.kind.into();
         let kind = if self.file.is_dir{
             FileType::Directory
@@ -874,14 +1774,10 @@ impl AlistFile {
             FileType::RegularFile
         };
         
-        let perm = if matches!(kind, FileType::Directory) {
-            0o755
-        } else {
-            0o644
-        };
+        let perm = permissions.mode(self.file.is_dir);
         let nlink = if ino == FUSE_ROOT_ID { 2 } else { 1 };
-        let uid = unsafe { libc::getuid() };
-        let gid = unsafe { libc::getgid() };
+        let uid = permissions.uid;
+        let gid = permissions.gid;
         let blksize = BLOCK_SIZE;
         let blocks = self.file.size / blksize + 1;
         FileAttr {